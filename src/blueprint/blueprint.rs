@@ -1,5 +0,0 @@
-use crate::models::Template;
-
-pub struct Blueprint {
-    templates: Vec<Template>,
-}
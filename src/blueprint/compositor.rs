@@ -1,42 +1,62 @@
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::slice;
 use std::sync::Arc;
+use std::time::Instant;
 
-use azure_storage_blobs::prelude::BlobServiceClient;
+use azure_storage_blobs::{blob::CopyStatus, prelude::BlobServiceClient};
 use bson::oid::ObjectId;
-use futures::TryStreamExt;
 use image::codecs::png::PngEncoder;
 use image::imageops::FilterType;
 use image::{imageops, RgbaImage};
 use imageproc::geometric_transformations::Interpolation;
 use itertools::{Itertools, MultiProduct};
-use mongodb::bson::doc;
+use sha2::{Digest, Sha256};
 use time::OffsetDateTime;
 
-use crate::db::{CompositorRun, CompositorRunStatus};
-use crate::models::{Degrees, Scale, Template};
+use crate::db::{self, CompositorRunStatus, ResolvedAlias, RunError, RunOutput, RunStage};
+use crate::models::{Degrees, ErrorPolicy, Opacity, Scale, Template};
 use crate::util::Result;
 
-use super::image_cache::ImageCache;
+use super::events::{RunEventKind, RunEvents};
+use super::image_cache::{AssetRef, ImageCache};
 
-#[derive(Debug, Clone)]
+/// Bump whenever a change to rendering means the same inputs no longer produce the same output,
+/// so incremental runs stop reusing outputs rendered by older versions.
+pub const COMPOSITOR_VERSION: u32 = 1;
+
+#[derive(Clone)]
 pub struct Compositor {
     db: mongodb::Client,
     blob_client: BlobServiceClient,
+    image_cache: Arc<ImageCache>,
+    events: RunEvents,
 }
 
 impl Compositor {
-    pub fn new(db: mongodb::Client, blob_client: BlobServiceClient) -> Compositor {
-        Compositor { db, blob_client }
+    pub fn new(
+        db: mongodb::Client,
+        blob_client: BlobServiceClient,
+        image_cache: Arc<ImageCache>,
+        events: RunEvents,
+    ) -> Compositor {
+        Compositor {
+            db,
+            blob_client,
+            image_cache,
+            events,
+        }
+    }
+
+    pub fn events(&self) -> &RunEvents {
+        &self.events
     }
 
     pub async fn apply_template_instance(
         &self,
         template: &Template,
-        image_cache: &ImageCache,
-        aliases: &HashMap<&String, &(&str, String)>,
-    ) -> Result<RgbaImage> {
+        aliases: &HashMap<&String, &AssetRef>,
+    ) -> std::result::Result<RgbaImage, RunError> {
         let (w, h) = (template.canvas_size.0, template.canvas_size.1);
         let mut canvas = RgbaImage::new(w, h);
         for pixel in canvas.pixels_mut() {
@@ -44,22 +64,23 @@ impl Compositor {
         }
 
         for layer_spec in &template.layers {
-            let (pack, path) = aliases.get(&&layer_spec.reference).unwrap();
-            let layer = image_cache
-                .inner
-                .get((pack.to_string(), path.to_string()))
-                .await?;
-            let layer = scale(&layer, layer_spec.transform.scale);
-            let mut layer = rot(&layer, layer_spec.transform.rotate);
-            for pixel in layer.pixels_mut() {
-                pixel[3] = (pixel[3] as f32 * layer_spec.opacity.0) as u8;
-            }
+            let asset = aliases.get(&layer_spec.reference).ok_or_else(|| {
+                RunError::new(
+                    RunStage::Compose,
+                    format!("layer uses undefined alias {}", &layer_spec.reference),
+                )
+            })?;
+            let layer = self
+                .image_cache
+                .get_layer(asset, &layer_spec.transform, layer_spec.opacity)
+                .await
+                .stage(RunStage::Load)?;
             // Need additional offsets to recenter after rotation happened
             let (lw, lh) = (layer.width() as i64, layer.height() as i64);
             let (cx, cy) = ((w as i64 / 2) - (lw / 2), (h as i64 / 2) - (lh / 2));
             imageops::overlay(
                 &mut canvas,
-                &layer,
+                layer.as_ref(),
                 layer_spec.transform.offset.0 + cx,
                 layer_spec.transform.offset.1 + cy,
             );
@@ -68,116 +89,277 @@ impl Compositor {
         Ok(canvas)
     }
 
-    pub async fn run_template(&self, template: Template) -> Result<(ObjectId, Result<()>)> {
-        let runs_coll = self
-            .db
-            .default_database()
-            .unwrap()
-            .collection::<CompositorRun>("runs");
-        let run = CompositorRun {
-            id: Default::default(),
-            created: OffsetDateTime::now_utc().into(),
-            status: CompositorRunStatus::Running,
-        };
-        let result = runs_coll.insert_one(&run, None).await?;
-        let id = result.inserted_id.as_object_id().unwrap();
-        Ok((id, self.run_template_internal(id, template).await))
-    }
-
-    pub async fn run_template_internal(
-        &self,
-        run_id: ObjectId,
-        mut template: Template,
-    ) -> Result<()> {
-        let runs_coll = self
-            .db
-            .default_database()
-            .unwrap()
-            .collection::<CompositorRun>("runs");
-        let output = self.blob_client.container_client("template-output");
-
+    /// Renders every combination of a run's template, binding its pinned assets where given
+    /// instead of expanding aliases against the current contents of their packs.
+    pub async fn run_template(&self, run: db::CompositorRun) -> Result<()> {
+        let (run_id, mut template, pinned_assets) = (run.id, run.template, run.pinned_assets);
+        // Every write is made on behalf of the worker holding the lease, so that a worker which
+        // lost its lease cannot interfere with the one the run was handed to next
+        let worker = run
+            .lease_owner
+            .ok_or_else(|| format!("template run {} is not leased to a worker", &run_id))?;
         template.normalize_use_refs();
         let mut expanded_refs = HashMap::new();
         for (alias, refs) in template.aliases.iter() {
-            expanded_refs.insert(alias, self.expand_refs(refs.iter()).await?);
+            let name = alias_name(alias);
+            let pinned = pinned_assets
+                .iter()
+                .flatten()
+                .find(|resolved| resolved.alias == name);
+            let expanded = match pinned {
+                Some(resolved) => self.verify_pinned_assets(&resolved.assets).await,
+                None => self.expand_refs(refs.iter()).await,
+            };
+            match expanded.map_err(|e| format!("{}: {}", name, e)) {
+                Ok(expanded) => {
+                    expanded_refs.insert(alias, expanded);
+                }
+                Err(e) => {
+                    let error = RunError::new(RunStage::Expand, e);
+                    return self
+                        .finish_run(run_id, &worker, CompositorRunStatus::Failed, Some(error))
+                        .await;
+                }
+            }
+        }
+
+        let total = expanded_refs
+            .values()
+            .map(|refs| refs.len() as u64)
+            .product();
+        let resolved_assets: Vec<_> = expanded_refs
+            .iter()
+            .map(|(alias, assets)| ResolvedAlias {
+                alias: alias_name(alias),
+                assets: assets.clone(),
+            })
+            .collect();
+        if !db::start_run(&self.db, run_id, &worker, total, &resolved_assets).await? {
+            log::info!("template run {} is no longer leased, stopping", &run_id);
+            return Ok(());
         }
 
+        let (mut completed, mut failed) = (0, 0);
         let (vals, iter) = iter_alias_binds(&expanded_refs);
         for tuple in iter {
             let pairs = vals.iter().zip(tuple).map(|(k, v)| (*k, v));
             let aliases = HashMap::from_iter(pairs);
 
-            let image_cache = ImageCache::new(Arc::new(self.blob_client.clone()));
+            let started = Instant::now();
+            let key = cache_key(&template, &aliases);
+            let mut reused_from = None;
+            let result = match output_blob_name(run_id, &aliases) {
+                Ok(blob_name) => {
+                    let reused = match run.incremental {
+                        true => self.reuse_output(run_id, &blob_name, &key).await,
+                        false => None,
+                    };
+                    match reused {
+                        Some((size, source)) => {
+                            reused_from = Some(source);
+                            Ok((blob_name, size))
+                        }
+                        None => self
+                            .render_output(&blob_name, &template, &aliases)
+                            .await
+                            .map(|size| (blob_name, size)),
+                    }
+                }
+                Err(e) => Err(e),
+            };
 
-            let result = self
-                .apply_template_instance(&template, &image_cache, &aliases)
-                .await?;
+            let (blob_name, size, error) = match result {
+                Ok((blob_name, size)) => (Some(blob_name), Some(size), None),
+                Err(e) => (None, None, Some(e)),
+            };
+            let output = RunOutput {
+                run_id,
+                bindings: binding_names(&aliases),
+                blob_name,
+                size,
+                duration_ms: started.elapsed().as_millis() as u64,
+                error,
+                created: OffsetDateTime::now_utc().into(),
+                cache_key: Some(key),
+                reused_from,
+            };
 
-            let mut buf = Vec::new();
-            result.write_with_encoder(PngEncoder::new(&mut buf))?;
+            if !db::record_output(&self.db, &worker, &output).await? {
+                log::info!("template run {} is no longer running, stopping", &run_id);
+                return self.clean_up_stopped_run(run_id).await;
+            }
 
-            let file_name = match aliases.get(&"$_fg".to_string()) {
-                Some((_, path)) => path,
-                None => panic!("erm"),
-            };
-            let relative_blob_name = run_id.to_string() + "/" + &file_name;
-            output
-                .blob_client(relative_blob_name)
-                .put_block_blob(buf)
-                .await?;
-
-            let modifications = doc! {
-                "$set": {
-                    "status": CompositorRunStatus::Running,
+            match output.error {
+                None => {
+                    completed += 1;
+                    self.events.publish(
+                        run_id,
+                        RunEventKind::Output {
+                            blob_name: output.blob_name.unwrap_or_default(),
+                            bindings: output.bindings,
+                        },
+                    );
                 }
-            };
+                Some(mut error) => {
+                    failed += 1;
+                    error.bindings = Some(output.bindings);
+                    if template.on_error == ErrorPolicy::FailFast {
+                        let status = CompositorRunStatus::Failed;
+                        return self.finish_run(run_id, &worker, status, Some(error)).await;
+                    }
+                    if !db::record_run_error(&self.db, run_id, &worker, &error).await? {
+                        log::info!("template run {} is no longer leased, stopping", &run_id);
+                        return self.clean_up_stopped_run(run_id).await;
+                    }
+                    self.events.publish(run_id, RunEventKind::Error { error });
+                }
+            }
+        }
 
-            runs_coll
-                .update_one(doc! { "_id": &run_id }, modifications, None)
-                .await?;
+        let status = match (completed, failed) {
+            (_, 0) => CompositorRunStatus::Succeeded,
+            (0, _) => CompositorRunStatus::Failed,
+            _ => CompositorRunStatus::CompletedWithErrors,
+        };
+        self.finish_run(run_id, &worker, status, None).await?;
+
+        let stats = self.image_cache.stats();
+        log::info!(
+            "template run {} done, image cache totals since start: assets {}/{} hits/misses, layers {}/{} hits/misses",
+            &run_id,
+            stats.assets.hits,
+            stats.assets.misses,
+            stats.layers.hits,
+            stats.layers.misses,
+        );
+        Ok(())
+    }
+
+    /// Deletes outputs uploaded after a run was cancelled, if the cancellation asked for it.
+    async fn clean_up_stopped_run(&self, run_id: ObjectId) -> Result<()> {
+        let run = db::find_run(&self.db, run_id).await?;
+        match run {
+            Some(run) if run.status == CompositorRunStatus::Cancelled && run.delete_outputs => {
+                db::delete_run_blobs(&self.blob_client, run_id).await
+            }
+            _ => Ok(()),
         }
+    }
 
-        let modifications = doc! {
-            "$set": {
-                "status": CompositorRunStatus::Succeeded,
+    /// Moves a running run to its final status, recording the error which ended it, if any.
+    async fn finish_run(
+        &self,
+        run_id: ObjectId,
+        worker: &str,
+        status: CompositorRunStatus,
+        error: Option<RunError>,
+    ) -> Result<()> {
+        if let Some(error) = error {
+            if !db::record_run_error(&self.db, run_id, worker, &error).await? {
+                return Ok(());
             }
-        };
-        runs_coll
-            .update_one(doc! { "_id": &run_id }, modifications, None)
-            .await?;
+            self.events.publish(run_id, RunEventKind::Error { error });
+        }
+        let running = &[CompositorRunStatus::Running];
+        if db::transition_run(&self.db, run_id, worker, running, status).await? {
+            self.events.status(run_id, status);
+        }
         Ok(())
     }
 
-    async fn match_paths_to_glob<'a>(
+    /// Copies the output of another run which was rendered from the same inputs to `blob_name`,
+    /// returning its size and the run it was copied from. Any failure just means the output has
+    /// to be rendered after all.
+    async fn reuse_output(
         &self,
-        pack_id: &'a str,
-        glob: &str,
-    ) -> Result<impl IntoIterator<Item = (&'a str, String)>> {
-        let matcher = globset::Glob::new(glob)?.compile_matcher();
-        let mut blobs = self
-            .blob_client
-            .container_client(format!("pack-{}", pack_id))
-            .list_blobs()
-            .into_stream();
-
-        let mut results = Vec::new();
-        while let Some(page) = blobs.try_next().await? {
-            for blob in page.blobs.blobs() {
-                if matcher.is_match(&blob.name) {
-                    results.push((pack_id, blob.name.to_string()));
+        run_id: ObjectId,
+        blob_name: &str,
+        cache_key: &str,
+    ) -> Option<(u64, ObjectId)> {
+        let previous = match db::find_reusable_output(&self.db, run_id, cache_key).await {
+            Ok(previous) => previous?,
+            Err(e) => {
+                log::warn!("could not look up reusable output for {}: {}", blob_name, e);
+                return None;
+            }
+        };
+
+        let container = self.blob_client.container_client("template-output");
+        let source = container
+            .blob_client(previous.blob_name.as_deref()?)
+            .url()
+            .ok()?;
+        let destination = container.blob_client(blob_name);
+        let copy = destination.copy(source).await.ok()?;
+        match copy.copy_status {
+            CopyStatus::Success => Some((previous.size.unwrap_or_default(), previous.run_id)),
+            CopyStatus::Pending => {
+                // May never finish if the previous run expires meanwhile, so the output is
+                // rendered instead of waiting for it
+                if let Err(e) = destination.delete().await {
+                    log::warn!("could not drop pending copy to {}: {}", blob_name, e);
                 }
+                None
             }
+            _ => None,
         }
-
-        Ok(results)
     }
 
-    async fn expand_ref<'a, S>(
+    /// Renders and uploads a single combination to `blob_name`, returning its size in bytes.
+    async fn render_output(
         &self,
-        item: &'a S,
-    ) -> Result<impl IntoIterator<Item = (&'a str, String)>>
+        blob_name: &str,
+        template: &Template,
+        aliases: &HashMap<&String, &AssetRef>,
+    ) -> std::result::Result<u64, RunError> {
+        let result = self.apply_template_instance(template, aliases).await?;
+
+        let mut buf = Vec::new();
+        result
+            .write_with_encoder(PngEncoder::new(&mut buf))
+            .stage(RunStage::Encode)?;
+        let size = buf.len() as u64;
+
+        self.blob_client
+            .container_client("template-output")
+            .blob_client(blob_name)
+            .put_block_blob(buf)
+            .content_type(db::OUTPUT_CONTENT_TYPE)
+            .await
+            .stage(RunStage::Upload)?;
+
+        Ok(size)
+    }
+
+    async fn match_paths_to_glob(&self, pack_id: &str, glob: &str) -> Result<Vec<AssetRef>> {
+        match_paths_to_glob(&self.blob_client, pack_id, glob).await
+    }
+
+    /// Checks that assets pinned without a blob version have not changed since they were
+    /// resolved, as only their current contents can be loaded.
+    async fn verify_pinned_assets(&self, assets: &[AssetRef]) -> Result<Vec<AssetRef>> {
+        for asset in assets.iter().filter(|a| a.version_id.is_none()) {
+            let properties = self
+                .blob_client
+                .container_client(format!("pack-{}", asset.pack))
+                .blob_client(&asset.path)
+                .get_properties()
+                .await?
+                .blob
+                .properties;
+            if properties.etag.to_string().trim_matches('"') != asset.etag.trim_matches('"') {
+                Err(format!(
+                    "{}:{} has changed and the pack does not keep blob versions",
+                    asset.pack, asset.path
+                ))?
+            }
+        }
+        Ok(assets.to_vec())
+    }
+
+    async fn expand_ref<S>(&self, item: &S) -> Result<Vec<AssetRef>>
     where
-        S: Borrow<str> + 'a,
+        S: Borrow<str>,
     {
         let iter = match item.borrow().split_once(':') {
             Some((slug, glob)) => self.match_paths_to_glob(slug, glob).await?,
@@ -186,10 +368,7 @@ impl Compositor {
         Ok(iter)
     }
 
-    async fn expand_refs<'a, S>(
-        &self,
-        refs: impl Iterator<Item = &'a S>,
-    ) -> Result<Vec<(&'a str, String)>>
+    async fn expand_refs<'a, S>(&self, refs: impl Iterator<Item = &'a S>) -> Result<Vec<AssetRef>>
     where
         S: Borrow<str> + 'a,
     {
@@ -204,18 +383,104 @@ impl Compositor {
     }
 }
 
+/// Finds the assets of a pack whose paths match a glob.
+pub async fn match_paths_to_glob(
+    blob_client: &BlobServiceClient,
+    pack_id: &str,
+    glob: &str,
+) -> Result<Vec<AssetRef>> {
+    let blobs = db::find_pack_blobs(blob_client, pack_id, glob).await?;
+    let assets = blobs
+        .into_iter()
+        .map(|blob| AssetRef {
+            pack: pack_id.to_string(),
+            path: blob.name,
+            etag: blob.properties.etag.to_string(),
+            version_id: blob.version_id,
+        })
+        .collect();
+    Ok(assets)
+}
+
+/// Every combination of bindings for a set of aliases, in the order of the accompanying keys.
+pub type AliasBinds<'b> = MultiProduct<slice::Iter<'b, AssetRef>>;
+
 /// Returns an iterator over all the possible alias bindings for the given mapping.
 pub fn iter_alias_binds<'a, 'b>(
-    aliases: &'b HashMap<&'a String, Vec<(&'a str, String)>>,
-) -> (
-    Vec<&'a String>,
-    MultiProduct<slice::Iter<'b, (&'a str, String)>>,
-) {
+    aliases: &'b HashMap<&'a String, Vec<AssetRef>>,
+) -> (Vec<&'a String>, AliasBinds<'b>) {
     let (keys, values): (Vec<&'a String>, Vec<_>) = aliases.iter().unzip();
-    let result = values.iter().map(|v| *v).multi_cartesian_product();
+    let result = values.iter().copied().multi_cartesian_product();
     (keys, result)
 }
 
+/// Applies a layer's scale, rotation and opacity to an asset.
+pub fn prepare_layer(image: &RgbaImage, s: Scale, r: Degrees, opacity: Opacity) -> RgbaImage {
+    let layer = scale(image, s);
+    let mut layer = rot(&layer, r);
+    for pixel in layer.pixels_mut() {
+        pixel[3] = (pixel[3] as f32 * opacity.0) as u8;
+    }
+    layer
+}
+
+/// Tags errors with the stage of the run they happened in.
+trait StageExt<T> {
+    fn stage(self, stage: RunStage) -> std::result::Result<T, RunError>;
+}
+
+impl<T, E: std::fmt::Display> StageExt<T> for std::result::Result<T, E> {
+    fn stage(self, stage: RunStage) -> std::result::Result<T, RunError> {
+        self.map_err(|e| RunError::new(stage, e))
+    }
+}
+
+/// Maps each alias, as it was written in the template, to the `pack:path` it was bound to.
+fn binding_names(aliases: &HashMap<&String, &AssetRef>) -> BTreeMap<String, String> {
+    aliases
+        .iter()
+        .map(|(alias, asset)| (alias_name(alias), format!("{}:{}", asset.pack, asset.path)))
+        .collect()
+}
+
+/// Names the output of a combination after the asset bound to `$fg`.
+fn output_blob_name(
+    run_id: ObjectId,
+    aliases: &HashMap<&String, &AssetRef>,
+) -> std::result::Result<String, RunError> {
+    match aliases.get(&"$_fg".to_string()) {
+        Some(asset) => Ok(run_id.to_string() + "/" + &asset.path),
+        None => Err(RunError::new(
+            RunStage::Compose,
+            "template has no $fg alias to name outputs after",
+        )),
+    }
+}
+
+/// Identifies everything that goes into rendering a combination: the compositor version, the
+/// canvas and layers, and the exact version of every bound asset. Alias definitions themselves
+/// are left out, since only what they are bound to matters.
+fn cache_key(template: &Template, aliases: &HashMap<&String, &AssetRef>) -> String {
+    let bindings: BTreeMap<_, _> = aliases
+        .iter()
+        .map(|(alias, asset)| (alias.as_str(), (&asset.pack, &asset.path, &asset.etag)))
+        .collect();
+    let inputs = (&template.canvas_size, &template.layers, bindings);
+
+    let mut hasher = Sha256::new();
+    hasher.update(COMPOSITOR_VERSION.to_le_bytes());
+    hasher.update(serde_json::to_vec(&inputs).expect("template is always serializable"));
+    hex::encode(hasher.finalize())
+}
+
+/// Undoes the underscore inserted into aliases by `Template::normalize_use_refs`.
+fn alias_name(alias: &str) -> String {
+    match alias.strip_prefix("$_") {
+        Some(name) => format!("${}", name),
+        None => alias.to_string(),
+    }
+}
+
 fn copy_to_center(src: &RgbaImage, dest: &mut RgbaImage) {
     let (sx, sy) = (src.width() / 2, src.height() / 2);
     let (dx, dy) = (dest.width() / 2, dest.height() / 2);
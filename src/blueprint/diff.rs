@@ -0,0 +1,78 @@
+use image::{Rgba, RgbaImage};
+use serde::Serialize;
+
+/// How much two renders of the same output differ.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ImageDiff {
+    /// Pixels where any channel differs by more than the tolerance, including pixels only one of
+    /// the images covers when their sizes differ.
+    pub different_pixels: u64,
+    pub total_pixels: u64,
+    /// The mean absolute difference over all channels of all pixels, from 0 to 1.
+    pub mean_difference: f64,
+}
+
+impl ImageDiff {
+    pub fn is_identical(&self) -> bool {
+        self.different_pixels == 0
+    }
+}
+
+/// Compares two images pixel by pixel, ignoring channel differences up to `tolerance`.
+pub fn diff_images(a: &RgbaImage, b: &RgbaImage, tolerance: u8) -> ImageDiff {
+    let (width, height) = (a.width().max(b.width()), a.height().max(b.height()));
+    let mut different_pixels = 0;
+    let mut total_difference = 0u64;
+
+    for y in 0..height {
+        for x in 0..width {
+            let delta = pixel_delta(pixel_at(a, x, y), pixel_at(b, x, y));
+            if delta.iter().any(|d| *d > tolerance) {
+                different_pixels += 1;
+            }
+            total_difference += delta.iter().map(|d| *d as u64).sum::<u64>();
+        }
+    }
+
+    let total_pixels = width as u64 * height as u64;
+    let mean_difference = match total_pixels {
+        0 => 0.0,
+        n => total_difference as f64 / (n * 4 * 255) as f64,
+    };
+    ImageDiff {
+        different_pixels,
+        total_pixels,
+        mean_difference,
+    }
+}
+
+/// Draws `b` faded out, with every pixel that differs from `a` by more than `tolerance` in red.
+pub fn highlight_diff(a: &RgbaImage, b: &RgbaImage, tolerance: u8) -> RgbaImage {
+    let (width, height) = (a.width().max(b.width()), a.height().max(b.height()));
+    RgbaImage::from_fn(width, height, |x, y| {
+        let (pa, pb) = (pixel_at(a, x, y), pixel_at(b, x, y));
+        if pixel_delta(pa, pb).iter().any(|d| *d > tolerance) {
+            return Rgba([255, 0, 0, 255]);
+        }
+        let [r, g, b, _] = pb.0;
+        let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+        let faded = (128 + luma / 2) as u8;
+        Rgba([faded, faded, faded, 255])
+    })
+}
+
+/// Pixels outside of an image count as fully transparent.
+fn pixel_at(image: &RgbaImage, x: u32, y: u32) -> Rgba<u8> {
+    match x < image.width() && y < image.height() {
+        true => *image.get_pixel(x, y),
+        false => Rgba([0, 0, 0, 0]),
+    }
+}
+
+fn pixel_delta(a: Rgba<u8>, b: Rgba<u8>) -> [u8; 4] {
+    let mut delta = [0; 4];
+    for (i, d) in delta.iter_mut().enumerate() {
+        *d = a.0[i].abs_diff(b.0[i]);
+    }
+    delta
+}
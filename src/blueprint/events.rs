@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+use bson::oid::ObjectId;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::db::{CompositorRunStatus, RunError};
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct RunEvent {
+    pub run_id: ObjectId,
+    pub kind: RunEventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RunEventKind {
+    Status {
+        status: CompositorRunStatus,
+    },
+    Output {
+        blob_name: String,
+        bindings: BTreeMap<String, String>,
+    },
+    Error {
+        error: RunError,
+    },
+}
+
+impl RunEventKind {
+    /// The name of the event when sent as a server-sent event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RunEventKind::Status { .. } => "status",
+            RunEventKind::Output { .. } => "output",
+            RunEventKind::Error { .. } => "error",
+        }
+    }
+}
+
+/// Broadcasts progress of the runs processed by this instance to anyone listening.
+///
+/// Events only reach subscribers in the same process as the worker handling the run.
+#[derive(Debug, Clone)]
+pub struct RunEvents {
+    sender: broadcast::Sender<RunEvent>,
+}
+
+impl Default for RunEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunEvents {
+    pub fn new() -> RunEvents {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        RunEvents { sender }
+    }
+
+    pub fn publish(&self, run_id: ObjectId, kind: RunEventKind) {
+        // Nobody listening is fine
+        let _ = self.sender.send(RunEvent { run_id, kind });
+    }
+
+    pub fn status(&self, run_id: ObjectId, status: CompositorRunStatus) {
+        self.publish(run_id, RunEventKind::Status { status });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RunEvent> {
+        self.sender.subscribe()
+    }
+}
@@ -1,21 +1,97 @@
-use std::{io::Cursor, sync::Arc};
+use std::{
+    fmt,
+    future::Future,
+    hash::Hash,
+    io::Cursor,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-use azure_storage_blobs::prelude::BlobServiceClient;
+use azure_storage_blobs::prelude::{BlobServiceClient, VersionId};
 use cache_loader_async::{
-    backing::LruCacheBacking,
-    cache_api::{CacheEntry, LoadingCache},
+    backing::{BackingError, CacheBacking, NoMeta},
+    cache_api::{CacheEntry, CacheLoadingError, LoadingCache},
 };
+use futures::TryStreamExt;
 use image::RgbaImage;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Degrees, Opacity, Scale, Transform};
+
+use super::compositor::prepare_layer;
 
-const POOL_SIZE: usize = 20;
+/// Identifies a single version of an asset in a pack.
+///
+/// The etag is part of the key so that replacing a blob in a pack is never served from a stale
+/// cache entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AssetRef {
+    pub pack: String,
+    pub path: String,
+    pub etag: String,
+    /// Set if the pack keeps blob versions, in which case exactly this version is loaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_id: Option<String>,
+}
+
+type Entry = CacheEntry<Arc<RgbaImage>, CacheError>;
 
+/// Identifies an asset after its layer's scale, rotation and opacity have been applied.
+///
+/// Offsets are not part of the key as they only affect where the layer is placed on the canvas.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LayerKey {
+    pub asset: AssetRef,
+    scale: u32,
+    rotate: u32,
+    opacity: u32,
+}
+
+impl LayerKey {
+    pub fn new(asset: AssetRef, transform: &Transform, opacity: Opacity) -> Self {
+        Self {
+            asset,
+            scale: transform.scale.0.to_bits(),
+            rotate: transform.rotate.0.to_bits(),
+            opacity: opacity.0.to_bits(),
+        }
+    }
+
+    pub fn scale(&self) -> Scale {
+        Scale(f32::from_bits(self.scale))
+    }
+
+    pub fn rotate(&self) -> Degrees {
+        Degrees(f32::from_bits(self.rotate))
+    }
+
+    pub fn opacity(&self) -> Opacity {
+        Opacity(f32::from_bits(self.opacity))
+    }
+}
+
+/// A process-wide cache shared by every compositor worker.
+///
+/// Lookups go through two tiers: decoded assets, and layers which have already been scaled,
+/// rotated and faded so repeated layers across combinations are only transformed once.
 pub struct ImageCache {
-    pub inner: LoadingCache<
-        (String, String),
-        RgbaImage,
-        CacheError,
-        LruCacheBacking<(String, String), CacheEntry<RgbaImage, CacheError>>,
-    >,
+    assets: Arc<CacheTier<AssetRef>>,
+    layers: CacheTier<LayerKey>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CacheStats {
+    pub assets: TierStats,
+    pub layers: TierStats,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct TierStats {
+    pub hits: u64,
+    pub misses: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -31,28 +107,237 @@ impl<E: std::error::Error> From<E> for CacheError {
     }
 }
 
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
 impl ImageCache {
-    pub fn new(blobs: Arc<BlobServiceClient>) -> ImageCache {
-        let inner = LoadingCache::with_backing(
-            LruCacheBacking::new(POOL_SIZE),
-            move |(pack, path): (String, String)| {
-                let blobs = blobs.clone();
+    /// Creates a cache which keeps at most `asset_budget` bytes of decoded assets and
+    /// `layer_budget` bytes of transformed layers in memory.
+    pub fn new(blobs: Arc<BlobServiceClient>, asset_budget: usize, layer_budget: usize) -> Self {
+        let assets = Arc::new(CacheTier::new(asset_budget, move |asset: AssetRef| {
+            let blobs = blobs.clone();
+            async move {
+                let blob = blobs
+                    .container_client(format!("pack-{}", asset.pack))
+                    .blob_client(asset.path);
+                let mut get = blob.get();
+                if let Some(version_id) = asset.version_id {
+                    get = get.blob_versioning(VersionId::new(version_id));
+                }
+
+                let mut content = Vec::new();
+                let mut chunks = get.into_stream();
+                while let Some(chunk) = chunks.try_next().await? {
+                    content.extend(chunk.data.collect().await?);
+                }
+
+                let image = image::io::Reader::new(Cursor::new(content))
+                    .with_guessed_format()?
+                    .decode()?
+                    .into_rgba8();
+
+                Ok(Arc::new(image))
+            }
+        }));
+
+        let layers = {
+            let assets = assets.clone();
+            CacheTier::new(layer_budget, move |key: LayerKey| {
+                let assets = assets.clone();
                 async move {
-                    let content = blobs
-                        .container_client(format!("pack-{}", pack))
-                        .blob_client(path)
-                        .get_content()
-                        .await?;
-
-                    let image = image::io::Reader::new(Cursor::new(content))
-                        .with_guessed_format()?
-                        .decode()?
-                        .into_rgba8();
-
-                    Ok(image)
+                    let image = assets.get(key.asset.clone()).await?;
+                    let layer = prepare_layer(&image, key.scale(), key.rotate(), key.opacity());
+                    Ok(Arc::new(layer))
                 }
+            })
+        };
+
+        ImageCache { assets, layers }
+    }
+
+    /// Returns the asset as it is, before any transform.
+    pub async fn get_asset(&self, asset: &AssetRef) -> crate::util::Result<Arc<RgbaImage>> {
+        self.assets
+            .get(asset.clone())
+            .await
+            .map_err(|e| format!("failed to load {}:{}: {}", asset.pack, asset.path, e).into())
+    }
+
+    /// Returns the asset with the layer's transform and opacity applied.
+    pub async fn get_layer(
+        &self,
+        asset: &AssetRef,
+        transform: &Transform,
+        opacity: Opacity,
+    ) -> crate::util::Result<Arc<RgbaImage>> {
+        let key = LayerKey::new(asset.clone(), transform, opacity);
+        self.layers
+            .get(key)
+            .await
+            .map_err(|e| format!("failed to load {}:{}: {}", asset.pack, asset.path, e).into())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            assets: self.assets.stats(),
+            layers: self.layers.stats(),
+        }
+    }
+}
+
+/// A single loading cache with its own byte budget and hit/miss counters.
+struct CacheTier<K>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+{
+    inner: LoadingCache<K, Arc<RgbaImage>, CacheError, ByteBudgetBacking<K>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K> CacheTier<K>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+{
+    fn new<T, F>(budget: usize, loader: T) -> Self
+    where
+        F: Future<Output = std::result::Result<Arc<RgbaImage>, CacheError>> + Send + 'static,
+        T: Fn(K) -> F + Send + 'static,
+    {
+        CacheTier {
+            inner: LoadingCache::with_backing(ByteBudgetBacking::new(budget), loader),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    async fn get(&self, key: K) -> std::result::Result<Arc<RgbaImage>, CacheError> {
+        let meta = self.inner.get_with_meta(key).await.map_err(|e| match e {
+            CacheLoadingError::LoadingError(e) => e,
+            e => CacheError {
+                message: format!("image cache error: {:?}", e),
             },
-        );
-        ImageCache { inner }
+        })?;
+
+        let counter = if meta.cached {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        Ok(meta.result)
+    }
+
+    fn stats(&self) -> TierStats {
+        TierStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// An LRU backing which evicts entries once the decoded images it holds exceed a byte budget.
+pub struct ByteBudgetBacking<K: Hash + Eq> {
+    lru: LruCache<K, Entry>,
+    budget: usize,
+    size: usize,
+}
+
+impl<K: Hash + Eq> ByteBudgetBacking<K> {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            lru: LruCache::unbounded(),
+            budget,
+            size: 0,
+        }
+    }
+
+    fn entry_size(entry: &Entry) -> usize {
+        match entry {
+            CacheEntry::Loaded(image) => image.as_raw().len(),
+            CacheEntry::Loading(_) => 0,
+        }
+    }
+
+    fn evict(&mut self) {
+        // Always keep the most recent entry, even if it alone is over budget
+        while self.size > self.budget && self.lru.len() > 1 {
+            match self.lru.pop_lru() {
+                Some((_, entry)) => self.size -= Self::entry_size(&entry),
+                None => break,
+            }
+        }
+    }
+}
+
+impl<K> CacheBacking<K, Entry> for ByteBudgetBacking<K>
+where
+    K: Eq + Hash + Clone + Send,
+{
+    type Meta = NoMeta;
+
+    fn get_mut(&mut self, key: &K) -> Result<Option<&mut Entry>, BackingError> {
+        Ok(self.lru.get_mut(key))
+    }
+
+    fn get(&mut self, key: &K) -> Result<Option<&Entry>, BackingError> {
+        Ok(self.lru.get(key))
+    }
+
+    fn set(
+        &mut self,
+        key: K,
+        value: Entry,
+        _meta: Option<Self::Meta>,
+    ) -> Result<Option<Entry>, BackingError> {
+        self.size += Self::entry_size(&value);
+        let old = self.lru.put(key, value);
+        if let Some(old) = &old {
+            self.size -= Self::entry_size(old);
+        }
+        self.evict();
+        Ok(old)
+    }
+
+    fn remove(&mut self, key: &K) -> Result<Option<Entry>, BackingError> {
+        let old = self.lru.pop(key);
+        if let Some(old) = &old {
+            self.size -= Self::entry_size(old);
+        }
+        Ok(old)
+    }
+
+    fn contains_key(&mut self, key: &K) -> Result<bool, BackingError> {
+        Ok(self.lru.contains(key))
+    }
+
+    fn remove_if(
+        &mut self,
+        predicate: Box<dyn Fn((&K, &Entry)) -> bool + Send + Sync + 'static>,
+    ) -> Result<Vec<(K, Entry)>, BackingError> {
+        let keys: Vec<K> = self
+            .lru
+            .iter()
+            .filter(|(k, v)| predicate((k, v)))
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        let mut removed = Vec::new();
+        for key in keys {
+            if let Some(entry) = self.remove(&key)? {
+                removed.push((key, entry));
+            }
+        }
+        Ok(removed)
+    }
+
+    fn clear(&mut self) -> Result<(), BackingError> {
+        self.lru.clear();
+        self.size = 0;
+        Ok(())
     }
 }
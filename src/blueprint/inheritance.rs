@@ -0,0 +1,319 @@
+//! Saved templates which extend another saved template.
+//!
+//! A template extending another only needs to give what it changes. Its aliases are added to
+//! those of the base, replacing ones with the same name, and any other field it gives, such as
+//! `canvas_size`, replaces the base's. Layers are matched by name: a layer named like one of the
+//! base's layers overrides the fields it gives of that layer, down to single fields of objects
+//! such as its `transform`, and every other layer is drawn on top of the base's layers.
+//! Parameters are inherited in the same way as aliases.
+//!
+//! A template which does not give the version of its base follows the base's latest version, so
+//! runs record the versions they were merged from, and a new version of a base is only saved if
+//! the templates following it still validate.
+
+use std::collections::HashSet;
+
+use serde_json::{Map, Value};
+
+use crate::db::{self, BaseTemplate, TemplateSource};
+use crate::util::Result;
+
+use super::parameters;
+use super::validation::{self, Problem};
+
+/// How many templates a chain of templates extending each other may have.
+const MAX_DEPTH: usize = 8;
+
+/// A saved template merged with the templates it extends.
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    /// Extends nothing.
+    pub source: TemplateSource,
+    /// The versions of the templates which were merged into it, most derived first.
+    pub bases: Vec<BaseTemplate>,
+}
+
+/// Merges the template saved as `name` with the templates it extends, or returns the problem
+/// with its chain of base templates.
+///
+/// `pending` is a template which is about to be saved as the latest version of the named
+/// template, which is used in place of that template's saved latest version.
+pub async fn resolve(
+    db: &mongodb::Client,
+    name: &str,
+    source: &TemplateSource,
+    pending: Option<(&str, &TemplateSource)>,
+) -> Result<std::result::Result<Resolved, Problem>> {
+    let problem = |message: String| {
+        Ok(Err(Problem {
+            pointer: "/extends".to_string(),
+            message,
+        }))
+    };
+
+    let mut seen = HashSet::from([name.to_string()]);
+    // Most derived first
+    let mut chain = vec![source.clone()];
+    let mut bases = Vec::new();
+    while let Some(base) = chain.last().unwrap().extends.clone() {
+        if !seen.insert(base.name.clone()) {
+            return problem(format!("template {} extends itself", &base.name));
+        }
+        if chain.len() == MAX_DEPTH {
+            return problem(format!(
+                "templates may extend each other at most {} levels deep",
+                MAX_DEPTH - 1
+            ));
+        }
+        match pending {
+            Some((pending_name, pending))
+                if pending_name == base.name && base.version.is_none() =>
+            {
+                chain.push(pending.clone());
+                bases.push(base);
+                continue;
+            }
+            _ => {}
+        }
+        let saved = match db::find_template(db, &base.name).await? {
+            Some(saved) => saved,
+            None => return problem(format!("no saved template named {}", &base.name)),
+        };
+        let version = base.version.unwrap_or(saved.latest_version);
+        match saved.version(version) {
+            Some(version) => {
+                chain.push(version.source.clone());
+                bases.push(BaseTemplate {
+                    name: base.name,
+                    version: Some(version.version),
+                });
+            }
+            None => {
+                return problem(format!(
+                    "template {} has no version {}",
+                    &base.name, version
+                ))
+            }
+        }
+    }
+
+    let mut merged = chain.pop().unwrap();
+    while let Some(source) = chain.pop() {
+        merged = merge(merged, source);
+    }
+    Ok(Ok(Resolved {
+        source: merged,
+        bases,
+    }))
+}
+
+/// Checks the templates which follow the latest version of `name`, directly or through other
+/// templates, against `source` as its new latest version, returning the problems it would give
+/// them.
+pub async fn check_dependents(
+    db: &mongodb::Client,
+    name: &str,
+    source: &TemplateSource,
+) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+    let mut checked = HashSet::from([name.to_string()]);
+    let mut bases = vec![name.to_string()];
+    while let Some(base) = bases.pop() {
+        for dependent in db::find_extending_templates(db, &base).await? {
+            let latest = match dependent.latest() {
+                Some(latest) => &latest.source,
+                None => continue,
+            };
+            let follows = matches!(
+                &latest.extends,
+                Some(extends) if extends.name == base && extends.version.is_none()
+            );
+            if !follows || !checked.insert(dependent.name.clone()) {
+                continue;
+            }
+            bases.push(dependent.name.clone());
+
+            let broken = match resolve(db, &dependent.name, latest, Some((name, source))).await? {
+                Ok(resolved) => check_resolved(db, &resolved.source).await?,
+                Err(problem) => vec![problem],
+            };
+            problems.extend(broken.into_iter().map(|problem| Problem {
+                pointer: problem.pointer,
+                message: format!("breaks template {}: {}", &dependent.name, problem.message),
+            }));
+        }
+    }
+    Ok(problems)
+}
+
+/// Validates a merged template as it would be run with the defaults of its parameters.
+async fn check_resolved(db: &mongodb::Client, source: &TemplateSource) -> Result<Vec<Problem>> {
+    let problems = parameters::check_parameters(&source.parameters);
+    if !problems.is_empty() {
+        return Ok(problems);
+    }
+    match parameters::instantiate(&source.template, &source.parameters, &Map::new()) {
+        Ok(template) => Ok(validation::validate_template(db, &template).await?.errors),
+        Err(problems) => Ok(problems),
+    }
+}
+
+fn merge(base: TemplateSource, source: TemplateSource) -> TemplateSource {
+    let mut parameters = base.parameters;
+    parameters.extend(source.parameters);
+
+    let mut template = match base.template {
+        Value::Object(template) => template,
+        _ => Map::new(),
+    };
+    let overrides = match source.template {
+        Value::Object(overrides) => overrides,
+        other => {
+            // Not a template at all, which is reported when it is read
+            return TemplateSource {
+                template: other,
+                parameters,
+                extends: None,
+            };
+        }
+    };
+    for (key, value) in overrides {
+        match (key.as_str(), template.get_mut(&key), value) {
+            ("aliases", Some(Value::Object(aliases)), Value::Object(overrides)) => {
+                aliases.extend(overrides);
+            }
+            ("layers", Some(Value::Array(layers)), Value::Array(overrides)) => {
+                merge_layers(layers, overrides);
+            }
+            (_, _, value) => {
+                template.insert(key, value);
+            }
+        }
+    }
+
+    TemplateSource {
+        template: Value::Object(template),
+        parameters,
+        extends: None,
+    }
+}
+
+fn merge_layers(layers: &mut Vec<Value>, overrides: Vec<Value>) {
+    for layer in overrides {
+        let index = layer.get("name").and_then(Value::as_str).and_then(|name| {
+            layers
+                .iter()
+                .position(|base| base.get("name").and_then(Value::as_str) == Some(name))
+        });
+        match (index, layer) {
+            (Some(i), Value::Object(fields)) => {
+                // Only objects have a name to be found by
+                if let Value::Object(base) = &mut layers[i] {
+                    merge_fields(base, fields);
+                }
+            }
+            (_, layer) => layers.push(layer),
+        }
+    }
+}
+
+/// Overrides the fields of `base` with those of `overrides`, merging objects given by both so
+/// fields the override leaves out, such as the `scale` of a `transform`, are kept.
+fn merge_fields(base: &mut Map<String, Value>, overrides: Map<String, Value>) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(base)), Value::Object(overrides)) => merge_fields(base, overrides),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn source(template: Value) -> TemplateSource {
+        TemplateSource {
+            template,
+            parameters: Default::default(),
+            extends: None,
+        }
+    }
+
+    #[test]
+    fn merge_adds_aliases_and_replaces_other_fields() {
+        let base = json!({
+            "canvas_size": [100, 100],
+            "aliases": { "$fg": ["pack:fg/*.png"], "$bg": ["pack:bg.png"] },
+            "layers": [],
+        });
+        let child = json!({
+            "canvas_size": [200, 50],
+            "aliases": { "$bg": ["pack:other.png"], "$logo": ["pack:logo.png"] },
+        });
+
+        let merged = merge(source(base), source(child));
+        assert_eq!(
+            merged.template,
+            json!({
+                "canvas_size": [200, 50],
+                "aliases": {
+                    "$fg": ["pack:fg/*.png"],
+                    "$bg": ["pack:other.png"],
+                    "$logo": ["pack:logo.png"],
+                },
+                "layers": [],
+            })
+        );
+    }
+
+    #[test]
+    fn merge_layers_overrides_named_layers_field_by_field() {
+        let mut layers = vec![
+            json!({
+                "name": "background",
+                "use": "$bg",
+                "transform": { "scale": 2.0, "rotate": 90.0, "offset": [0, 0] },
+            }),
+            json!({ "name": "foreground", "use": "$fg" }),
+        ];
+        let overrides = vec![
+            json!({ "name": "background", "transform": { "offset": [10, 0] } }),
+            json!({ "name": "badge", "use": "pack:badge.png" }),
+            json!({ "use": "pack:frame.png" }),
+        ];
+
+        merge_layers(&mut layers, overrides);
+        assert_eq!(
+            layers,
+            vec![
+                json!({
+                    "name": "background",
+                    "use": "$bg",
+                    "transform": { "scale": 2.0, "rotate": 90.0, "offset": [10, 0] },
+                }),
+                json!({ "name": "foreground", "use": "$fg" }),
+                json!({ "name": "badge", "use": "pack:badge.png" }),
+                json!({ "use": "pack:frame.png" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_fields_replaces_scalars_and_arrays() {
+        let mut base = json!({ "opacity": 0.5, "transform": { "offset": [1, 2] } });
+        let overrides = json!({ "opacity": 1.0, "transform": { "offset": [3] } });
+        match (&mut base, overrides) {
+            (Value::Object(base), Value::Object(overrides)) => merge_fields(base, overrides),
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            base,
+            json!({ "opacity": 1.0, "transform": { "offset": [3] } })
+        );
+    }
+}
@@ -0,0 +1,456 @@
+//! Looks for parts of a template which do nothing to its outputs, using the dimensions of the
+//! assets its layers are bound to.
+
+use std::collections::{HashMap, HashSet};
+
+use azure_storage_blobs::prelude::BlobServiceClient;
+use futures::StreamExt;
+
+use crate::models::{BlendMode, Layer, Template};
+use crate::util::Result;
+
+use super::compositor::match_paths_to_glob;
+use super::image_cache::{AssetRef, ImageCache};
+use super::validation::{escape, Problem};
+
+/// How many assets are inspected at most, since each of them may have to be downloaded.
+const MAX_INSPECTED_ASSETS: usize = 200;
+const INSPECT_CONCURRENCY: usize = 8;
+/// How far off bounds worked out here may be from those of a rendered layer, from rounding and
+/// interpolation.
+const MARGIN: f64 = 1.0;
+
+/// What matters about an asset for where its layer ends up.
+#[derive(Debug, Clone, Copy)]
+struct AssetInfo {
+    width: u32,
+    height: u32,
+    /// Whether every pixel is fully opaque.
+    opaque: bool,
+}
+
+/// The area of the canvas a layer draws to.
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    left: f64,
+    top: f64,
+    right: f64,
+    bottom: f64,
+}
+
+impl Bounds {
+    fn union(self, other: Bounds) -> Bounds {
+        Bounds {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    fn intersection(self, other: Bounds) -> Bounds {
+        Bounds {
+            left: self.left.max(other.left),
+            top: self.top.max(other.top),
+            right: self.right.min(other.right),
+            bottom: self.bottom.min(other.bottom),
+        }
+    }
+
+    fn grow(self, by: f64) -> Bounds {
+        Bounds {
+            left: self.left - by,
+            top: self.top - by,
+            right: self.right + by,
+            bottom: self.bottom + by,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.left >= self.right || self.top >= self.bottom
+    }
+
+    fn contains(&self, other: &Bounds) -> bool {
+        self.left <= other.left
+            && self.top <= other.top
+            && self.right >= other.right
+            && self.bottom >= other.bottom
+    }
+}
+
+/// Warns about layers which are off the canvas, covered by an opaque layer above them or fully
+/// transparent, and aliases which only ever match a single asset. Expects a template which
+/// passed validation.
+pub async fn lint_template(
+    blobs: &BlobServiceClient,
+    image_cache: &ImageCache,
+    template: &Template,
+) -> Result<Vec<Problem>> {
+    let mut warnings = Vec::new();
+
+    let mut aliases: HashMap<&String, Vec<AssetRef>> = HashMap::new();
+    for (alias, refs) in &template.aliases {
+        let mut assets = Vec::new();
+        for reference in refs {
+            assets.extend(expand_ref(blobs, reference).await?);
+        }
+        aliases.insert(alias, assets);
+    }
+    let mut names: Vec<_> = aliases.keys().copied().collect();
+    names.sort();
+    for alias in names {
+        // $fg names the outputs, so it is an alias even when it matches a single asset
+        if alias != "$fg" && aliases[alias].len() == 1 {
+            warnings.push(Problem {
+                pointer: format!("/aliases/{}", escape(alias)),
+                message: format!(
+                    "alias {} only matches a single asset and could be a plain reference",
+                    alias
+                ),
+            });
+        }
+    }
+
+    let mut layers = Vec::new();
+    for layer in &template.layers {
+        let assets = match aliases.get(&layer.reference) {
+            Some(assets) => assets.clone(),
+            None => expand_ref(blobs, &layer.reference).await?,
+        };
+        layers.push(assets);
+    }
+
+    let mut visible = Vec::new();
+    for (i, layer) in template.layers.iter().enumerate() {
+        let see = layer.opacity.0 > 0.0;
+        if !see {
+            warnings.push(Problem {
+                pointer: format!("/layers/{}/opacity", i),
+                message: "layer is fully transparent".to_string(),
+            });
+        }
+        visible.push(see);
+    }
+
+    let mut assets: Vec<&AssetRef> = layers.iter().flatten().collect();
+    assets.sort_by(|a, b| (&a.pack, &a.path).cmp(&(&b.pack, &b.path)));
+    assets.dedup();
+    if assets.len() > MAX_INSPECTED_ASSETS {
+        warnings.push(Problem {
+            pointer: "/layers".to_string(),
+            message: format!(
+                "layers are bound to more than {} assets, so their placement was not checked",
+                MAX_INSPECTED_ASSETS
+            ),
+        });
+        return Ok(warnings);
+    }
+    let infos: HashMap<&AssetRef, std::result::Result<AssetInfo, String>> =
+        futures::stream::iter(assets)
+            .map(|asset| async move { (asset, inspect(image_cache, asset).await) })
+            .buffered(INSPECT_CONCURRENCY)
+            .collect()
+            .await;
+
+    let (width, height) = template.canvas_size;
+    let canvas = Bounds {
+        left: 0.0,
+        top: 0.0,
+        right: width as f64,
+        bottom: height as f64,
+    };
+    // Where each layer may draw, over every asset it can be bound to, clipped to the canvas
+    let mut drawn = Vec::new();
+    let mut unreadable = HashSet::new();
+    for (i, (layer, assets)) in template.layers.iter().zip(&layers).enumerate() {
+        let mut readable = Vec::new();
+        for asset in assets {
+            match &infos[asset] {
+                Ok(info) => readable.push(info),
+                Err(e) => {
+                    if unreadable.insert(asset) {
+                        warnings.push(Problem {
+                            pointer: format!("/layers/{}/use", i),
+                            // Names the asset already
+                            message: e.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        // Nothing is known about where the layer is drawn
+        if readable.is_empty() || readable.len() < assets.len() {
+            drawn.push(None);
+            continue;
+        }
+        let bounds = readable
+            .into_iter()
+            .map(|info| layer_bounds(template.canvas_size, layer, info))
+            .reduce(Bounds::union)
+            .map(|bounds| bounds.grow(MARGIN).intersection(canvas))
+            .filter(|bounds| !bounds.is_empty());
+        if bounds.is_none() && visible[i] {
+            warnings.push(Problem {
+                pointer: format!("/layers/{}/transform", i),
+                message: "layer is entirely outside the canvas".to_string(),
+            });
+        }
+        drawn.push(bounds);
+    }
+
+    for (i, bounds) in drawn.iter().enumerate() {
+        let bounds = match bounds {
+            Some(bounds) if visible[i] => bounds,
+            _ => continue,
+        };
+        let covered_by = (i + 1..template.layers.len()).find(|&above| {
+            coverage(
+                template.canvas_size,
+                &template.layers[above],
+                &layers[above],
+                &infos,
+            )
+            .is_some_and(|coverage| coverage.contains(bounds))
+        });
+        if let Some(above) = covered_by {
+            warnings.push(Problem {
+                pointer: format!("/layers/{}", i),
+                message: format!("layer is completely covered by layer {}", above),
+            });
+        }
+    }
+
+    Ok(warnings)
+}
+
+async fn expand_ref(blobs: &BlobServiceClient, reference: &str) -> Result<Vec<AssetRef>> {
+    match reference.split_once(':') {
+        Some((slug, glob)) => match_paths_to_glob(blobs, slug, glob).await,
+        None => Err(format!("reference is missing pack slug: {}", reference))?,
+    }
+}
+
+/// Loads an asset through the image cache to find its dimensions, so assets are not downloaded
+/// again by each validation or by the runs that follow it.
+async fn inspect(
+    image_cache: &ImageCache,
+    asset: &AssetRef,
+) -> std::result::Result<AssetInfo, String> {
+    let image = image_cache
+        .get_asset(asset)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(AssetInfo {
+        width: image.width(),
+        height: image.height(),
+        opaque: image.pixels().all(|p| p[3] == u8::MAX),
+    })
+}
+
+/// Works out where the compositor draws an asset, following `Compositor::apply_template_instance`:
+/// the asset is scaled, rotated about its center within a square fitting any rotation of it,
+/// and that square is centered on the canvas before being moved by the layer's offset.
+fn layer_bounds(canvas_size: (u32, u32), layer: &Layer, asset: &AssetInfo) -> Bounds {
+    let scale = layer.transform.scale.0;
+    let (w, h) = (
+        (asset.width as f32 * scale) as u32,
+        (asset.height as f32 * scale) as u32,
+    );
+    let side = (w as f64).hypot(h as f64) as u32;
+    let (offset_x, offset_y) = layer.transform.offset;
+    let left = offset_x + canvas_size.0 as i64 / 2 - side as i64 / 2;
+    let top = offset_y + canvas_size.1 as i64 / 2 - side as i64 / 2;
+    let (center_x, center_y) = (
+        left as f64 + side as f64 / 2.0,
+        top as f64 + side as f64 / 2.0,
+    );
+
+    let angle = (layer.transform.rotate.0 as f64).to_radians();
+    let (cos, sin) = (angle.cos().abs(), angle.sin().abs());
+    let half_width = (w as f64 * cos + h as f64 * sin) / 2.0;
+    let half_height = (w as f64 * sin + h as f64 * cos) / 2.0;
+    Bounds {
+        left: center_x - half_width,
+        top: center_y - half_height,
+        right: center_x + half_width,
+        bottom: center_y + half_height,
+    }
+}
+
+/// The area a layer is sure to cover with opaque pixels whichever asset it is bound to, if any.
+fn coverage(
+    canvas_size: (u32, u32),
+    layer: &Layer,
+    assets: &[AssetRef],
+    infos: &HashMap<&AssetRef, std::result::Result<AssetInfo, String>>,
+) -> Option<Bounds> {
+    // Rotations other than quarter turns leave transparent corners in the layer's bounds
+    let quarter_turns = layer.transform.rotate.0 / 90.0;
+    if layer.opacity.0 < 1.0
+        || !matches!(layer.blend_mode, BlendMode::Normal)
+        || (quarter_turns - quarter_turns.round()).abs() > 1e-3
+    {
+        return None;
+    }
+    assets
+        .iter()
+        .map(|asset| {
+            let info = infos[asset].as_ref().ok()?;
+            info.opaque
+                .then(|| layer_bounds(canvas_size, layer, info).grow(-MARGIN))
+        })
+        .reduce(|a, b| Some(a?.intersection(b?)))
+        .flatten()
+        .filter(|bounds| !bounds.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const CANVAS: (u32, u32) = (100, 100);
+
+    fn layer(layer: serde_json::Value) -> Layer {
+        serde_json::from_value(layer).unwrap()
+    }
+
+    fn asset(path: &str) -> AssetRef {
+        AssetRef {
+            pack: "cards".to_string(),
+            path: path.to_string(),
+            etag: "etag".to_string(),
+            version_id: None,
+        }
+    }
+
+    fn info(width: u32, height: u32, opaque: bool) -> AssetInfo {
+        AssetInfo {
+            width,
+            height,
+            opaque,
+        }
+    }
+
+    fn assert_bounds(bounds: Bounds, expected: (f64, f64, f64, f64)) {
+        let actual = (bounds.left, bounds.top, bounds.right, bounds.bottom);
+        let close = [
+            (actual.0, expected.0),
+            (actual.1, expected.1),
+            (actual.2, expected.2),
+            (actual.3, expected.3),
+        ]
+        .iter()
+        .all(|(a, b)| (a - b).abs() < 1e-6);
+        assert!(close, "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn layer_bounds_are_centered_and_offset() {
+        let centered = layer(json!({ "use": "$fg" }));
+        assert_bounds(
+            layer_bounds(CANVAS, &centered, &info(20, 10, true)),
+            (40.0, 45.0, 60.0, 55.0),
+        );
+
+        let moved =
+            layer(json!({ "use": "$fg", "transform": { "offset": [10, -5], "scale": 2.0 } }));
+        assert_bounds(
+            layer_bounds(CANVAS, &moved, &info(20, 10, true)),
+            (40.0, 35.0, 80.0, 55.0),
+        );
+    }
+
+    #[test]
+    fn layer_bounds_follow_rotation() {
+        let quarter = layer(json!({ "use": "$fg", "transform": { "rotate": 90.0 } }));
+        assert_bounds(
+            layer_bounds(CANVAS, &quarter, &info(20, 10, true)),
+            (45.0, 40.0, 55.0, 60.0),
+        );
+
+        // A square turned by 45 degrees reaches out to its diagonal
+        let eighth = layer(json!({ "use": "$fg", "transform": { "rotate": 45.0 } }));
+        let half_diagonal = 10.0 * 2f64.sqrt();
+        assert_bounds(
+            layer_bounds(CANVAS, &eighth, &info(20, 20, true)),
+            (
+                50.0 - half_diagonal,
+                50.0 - half_diagonal,
+                50.0 + half_diagonal,
+                50.0 + half_diagonal,
+            ),
+        );
+    }
+
+    #[test]
+    fn layer_off_the_canvas_has_no_bounds_on_it() {
+        let canvas = Bounds {
+            left: 0.0,
+            top: 0.0,
+            right: 100.0,
+            bottom: 100.0,
+        };
+        let off = layer(json!({ "use": "$fg", "transform": { "offset": [200, 0] } }));
+        let bounds = layer_bounds(CANVAS, &off, &info(20, 10, true));
+        assert!(bounds.grow(MARGIN).intersection(canvas).is_empty());
+
+        let edge = layer(json!({ "use": "$fg", "transform": { "offset": [55, 0] } }));
+        let bounds = layer_bounds(CANVAS, &edge, &info(20, 10, true));
+        assert!(!bounds.grow(MARGIN).intersection(canvas).is_empty());
+    }
+
+    #[test]
+    fn opaque_layer_above_covers_smaller_layer() {
+        let (background, small) = (asset("bg.png"), asset("small.png"));
+        let infos = HashMap::from([
+            (&background, Ok(info(100, 100, true))),
+            (&small, Ok(info(20, 10, true))),
+        ]);
+        let below = layer_bounds(CANVAS, &layer(json!({ "use": "$fg" })), &info(20, 10, true));
+
+        let above = layer(json!({ "use": "cards:bg.png" }));
+        let covered = coverage(CANVAS, &above, std::slice::from_ref(&background), &infos);
+        assert!(covered.is_some_and(|coverage| coverage.contains(&below)));
+
+        // Sure to cover only what every asset it may be bound to covers
+        let assets = [background.clone(), small.clone()];
+        let covered = coverage(CANVAS, &above, &assets, &infos);
+        assert!(covered.is_some_and(|coverage| !coverage.contains(&below)));
+
+        let quarter = layer(json!({ "use": "cards:bg.png", "transform": { "rotate": 90.0 } }));
+        assert!(coverage(CANVAS, &quarter, std::slice::from_ref(&background), &infos).is_some());
+    }
+
+    #[test]
+    fn layers_which_let_pixels_through_cover_nothing() {
+        let (background, unreadable) = (asset("bg.png"), asset("broken.png"));
+        let infos = HashMap::from([
+            (&background, Ok(info(100, 100, true))),
+            (&unreadable, Err("failed to load".to_string())),
+        ]);
+        let assets = std::slice::from_ref(&background);
+
+        for above in [
+            json!({ "use": "cards:bg.png", "opacity": 0.5 }),
+            json!({ "use": "cards:bg.png", "blend_mode": "Multiply" }),
+            json!({ "use": "cards:bg.png", "transform": { "rotate": 45.0 } }),
+        ] {
+            assert!(
+                coverage(CANVAS, &layer(above.clone()), assets, &infos).is_none(),
+                "{}",
+                above
+            );
+        }
+
+        let translucent = asset("glass.png");
+        let infos = HashMap::from([(&translucent, Ok(info(100, 100, false)))]);
+        let above = layer(json!({ "use": "cards:glass.png" }));
+        assert!(coverage(CANVAS, &above, std::slice::from_ref(&translucent), &infos).is_none());
+
+        let infos = HashMap::from([(&unreadable, Err("failed to load".to_string()))]);
+        let above = layer(json!({ "use": "cards:broken.png" }));
+        assert!(coverage(CANVAS, &above, std::slice::from_ref(&unreadable), &infos).is_none());
+    }
+}
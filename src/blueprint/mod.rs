@@ -1,3 +1,11 @@
-mod blueprint;
 pub mod compositor;
+pub mod diff;
+pub mod events;
 pub mod image_cache;
+pub mod inheritance;
+pub mod lint;
+pub mod parameters;
+pub mod retention;
+pub mod validation;
+pub mod webhooks;
+pub mod worker;
@@ -0,0 +1,328 @@
+//! Substitution of a saved template's parameters into its placeholders.
+//!
+//! A placeholder is written as `{{name}}`. A string consisting of just a placeholder is replaced
+//! by the parameter's value as is, so `"opacity": "{{fade}}"` becomes a number, while
+//! placeholders within a longer string are spliced into it, as in `"use": "{{pack}}:bg.png"`.
+
+use std::collections::BTreeMap;
+
+use serde_json::{Map, Value};
+use serde_path_to_error::Segment;
+
+use crate::models::{Parameter, ParameterType, Template};
+
+use super::validation::{escape, Problem};
+
+/// Checks that the default of every parameter has the parameter's type.
+pub fn check_parameters(parameters: &BTreeMap<String, Parameter>) -> Vec<Problem> {
+    parameters
+        .iter()
+        .filter_map(|(name, parameter)| {
+            let message = check_value(parameter.kind, &parameter.default).err()?;
+            Some(Problem {
+                pointer: format!("/parameters/{}/default", escape(name)),
+                message,
+            })
+        })
+        .collect()
+}
+
+/// Fills in the placeholders of a template with `values`, or the defaults of the parameters
+/// without one, and reads the result as a [`Template`].
+pub fn instantiate(
+    template: &Value,
+    parameters: &BTreeMap<String, Parameter>,
+    values: &Map<String, Value>,
+) -> Result<Template, Vec<Problem>> {
+    let mut problems = Vec::new();
+    for name in values.keys() {
+        if !parameters.contains_key(name) {
+            problems.push(Problem {
+                pointer: format!("/parameters/{}", escape(name)),
+                message: format!("unknown parameter {}", name),
+            });
+        }
+    }
+
+    let mut resolved = BTreeMap::new();
+    for (name, parameter) in parameters {
+        let value = values.get(name).unwrap_or(&parameter.default);
+        if let Err(message) = check_value(parameter.kind, value) {
+            problems.push(Problem {
+                pointer: format!("/parameters/{}", escape(name)),
+                message,
+            });
+        }
+        resolved.insert(name.as_str(), value);
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    let mut template = template.clone();
+    substitute(&mut template, &resolved, String::new(), &mut problems);
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    serde_path_to_error::deserialize(template).map_err(|e| {
+        vec![Problem {
+            pointer: to_pointer(e.path()),
+            message: e.inner().to_string(),
+        }]
+    })
+}
+
+fn check_value(kind: ParameterType, value: &Value) -> Result<(), String> {
+    let valid = match (kind, value) {
+        (ParameterType::Number, Value::Number(_)) => true,
+        (ParameterType::String, Value::String(_)) => true,
+        (ParameterType::Color, Value::String(s)) => is_color(s),
+        (ParameterType::Pack, Value::String(s)) => !s.is_empty() && slug::slugify(s) == *s,
+        _ => false,
+    };
+    match valid {
+        true => Ok(()),
+        false => Err(match kind {
+            ParameterType::Number => "must be a number".to_string(),
+            ParameterType::String => "must be a string".to_string(),
+            ParameterType::Color => "must be a color like #rrggbb or #rrggbbaa".to_string(),
+            ParameterType::Pack => "must be the slug of a pack".to_string(),
+        }),
+    }
+}
+
+fn is_color(value: &str) -> bool {
+    match value.strip_prefix('#') {
+        Some(hex) => matches!(hex.len(), 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+fn substitute(
+    value: &mut Value,
+    parameters: &BTreeMap<&str, &Value>,
+    pointer: String,
+    problems: &mut Vec<Problem>,
+) {
+    match value {
+        Value::String(s) => match replace_placeholders(s, parameters) {
+            Ok(Some(replacement)) => *value = replacement,
+            Ok(None) => {}
+            Err(message) => problems.push(Problem { pointer, message }),
+        },
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                substitute(item, parameters, format!("{}/{}", pointer, i), problems);
+            }
+        }
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                let pointer = format!("{}/{}", pointer, escape(key));
+                substitute(field, parameters, pointer, problems);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns what a string becomes once its placeholders are filled in, or `None` if it has none.
+fn replace_placeholders(
+    s: &str,
+    parameters: &BTreeMap<&str, &Value>,
+) -> Result<Option<Value>, String> {
+    let lookup = |name: &str| {
+        parameters
+            .get(name.trim())
+            .copied()
+            .ok_or_else(|| format!("undefined parameter {}", name.trim()))
+    };
+
+    if let Some(name) = s.strip_prefix("{{").and_then(|s| s.strip_suffix("}}")) {
+        if !name.contains("{{") && !name.contains("}}") {
+            return lookup(name).map(|value| Some(value.clone()));
+        }
+    }
+    if !s.contains("{{") {
+        return Ok(None);
+    }
+
+    let mut result = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => return Err("unterminated placeholder".to_string()),
+        };
+        result.push_str(&rest[..start]);
+        match lookup(&rest[start + 2..end])? {
+            Value::String(value) => result.push_str(value),
+            Value::Number(value) => result.push_str(&value.to_string()),
+            _ => return Err("only numbers and strings can be spliced into a string".to_string()),
+        }
+        rest = &rest[end + 2..];
+    }
+    result.push_str(rest);
+    Ok(Some(Value::String(result)))
+}
+
+fn to_pointer(path: &serde_path_to_error::Path) -> String {
+    path.iter()
+        .map(|segment| match segment {
+            Segment::Seq { index } => format!("/{}", index),
+            Segment::Map { key } => format!("/{}", escape(key)),
+            Segment::Enum { variant } => format!("/{}", escape(variant)),
+            Segment::Unknown => "/?".to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parameter(kind: ParameterType, default: Value) -> Parameter {
+        Parameter {
+            kind,
+            default,
+            description: None,
+        }
+    }
+
+    fn resolved(values: &Value) -> BTreeMap<&str, &Value> {
+        let values = values.as_object().unwrap();
+        values.iter().map(|(k, v)| (k.as_str(), v)).collect()
+    }
+
+    #[test]
+    fn check_value_by_type() {
+        assert!(check_value(ParameterType::Number, &json!(0.5)).is_ok());
+        assert!(check_value(ParameterType::Number, &json!("0.5")).is_err());
+        assert!(check_value(ParameterType::String, &json!("text")).is_ok());
+        assert!(check_value(ParameterType::String, &json!(1)).is_err());
+
+        assert!(check_value(ParameterType::Color, &json!("#a0b1c2")).is_ok());
+        assert!(check_value(ParameterType::Color, &json!("#A0B1C2FF")).is_ok());
+        for color in ["a0b1c2", "#a0b1c", "#a0b1c2f", "#g0b1c2"] {
+            assert!(
+                check_value(ParameterType::Color, &json!(color)).is_err(),
+                "{}",
+                color
+            );
+        }
+
+        assert!(check_value(ParameterType::Pack, &json!("fall-2023")).is_ok());
+        for pack in ["", "Fall 2023", "fall/2023"] {
+            assert!(
+                check_value(ParameterType::Pack, &json!(pack)).is_err(),
+                "{}",
+                pack
+            );
+        }
+    }
+
+    #[test]
+    fn whole_value_placeholders_keep_their_type() {
+        let values = json!({ "fade": 0.5, "pack": "cards" });
+        let parameters = resolved(&values);
+
+        assert_eq!(
+            replace_placeholders("{{fade}}", &parameters),
+            Ok(Some(json!(0.5)))
+        );
+        assert_eq!(
+            replace_placeholders("{{ pack }}", &parameters),
+            Ok(Some(json!("cards")))
+        );
+        assert_eq!(
+            replace_placeholders("no placeholders", &parameters),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn spliced_placeholders_become_strings() {
+        let values = json!({ "fade": 0.5, "pack": "cards", "list": [1] });
+        let parameters = resolved(&values);
+
+        assert_eq!(
+            replace_placeholders("{{pack}}:bg-{{fade}}.png", &parameters),
+            Ok(Some(json!("cards:bg-0.5.png")))
+        );
+        // Two placeholders are not a single one spanning the whole string
+        assert_eq!(
+            replace_placeholders("{{pack}}{{pack}}", &parameters),
+            Ok(Some(json!("cardscards")))
+        );
+        assert_eq!(
+            replace_placeholders("{{list}}.png", &parameters),
+            Err("only numbers and strings can be spliced into a string".to_string())
+        );
+        assert_eq!(
+            replace_placeholders("{{pack", &parameters),
+            Err("unterminated placeholder".to_string())
+        );
+        assert_eq!(
+            replace_placeholders("{{other}}:bg.png", &parameters),
+            Err("undefined parameter other".to_string())
+        );
+    }
+
+    #[test]
+    fn instantiate_fills_in_values_and_defaults() {
+        let parameters = BTreeMap::from([
+            (
+                "pack".to_string(),
+                parameter(ParameterType::Pack, json!("cards")),
+            ),
+            (
+                "fade".to_string(),
+                parameter(ParameterType::Number, json!(1.0)),
+            ),
+        ]);
+        let template = json!({
+            "aliases": { "$fg": ["{{pack}}:*.png"] },
+            "layers": [{ "use": "$fg", "opacity": "{{fade}}" }],
+            "canvas_size": [100, 100],
+        });
+        let values = json!({ "fade": 0.25 });
+
+        let template = instantiate(&template, &parameters, values.as_object().unwrap()).unwrap();
+        assert_eq!(template.aliases["$fg"], vec!["cards:*.png"]);
+        assert_eq!(template.layers[0].opacity.0, 0.25);
+    }
+
+    #[test]
+    fn instantiate_locates_problems() {
+        let parameters = BTreeMap::from([(
+            "fade".to_string(),
+            parameter(ParameterType::Number, json!(1.0)),
+        )]);
+        let template = json!({
+            "aliases": { "$fg": ["cards:*.png"] },
+            "layers": [{ "use": "$fg", "opacity": "{{fade}}" }],
+            "canvas_size": "{{size}}",
+        });
+
+        let problems = |values: Value| {
+            instantiate(&template, &parameters, values.as_object().unwrap())
+                .unwrap_err()
+                .into_iter()
+                .map(|problem| format!("{}: {}", problem.pointer, problem.message))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            problems(json!({ "fade": "half", "other": 1 })),
+            vec![
+                "/parameters/other: unknown parameter other",
+                "/parameters/fade: must be a number",
+            ]
+        );
+        assert_eq!(
+            problems(json!({})),
+            vec!["/canvas_size: undefined parameter size"]
+        );
+    }
+}
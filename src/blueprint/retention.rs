@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use azure_storage_blobs::prelude::BlobServiceClient;
+
+use crate::db;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Periodically deletes the outputs of runs which have outlived their retention.
+pub async fn sweep_expired_runs(db: mongodb::Client, blobs: BlobServiceClient) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        loop {
+            match expire_next_run(&db, &blobs).await {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    log::error!("failed to expire run: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Expires a single run, returning `false` if there was none left to expire.
+async fn expire_next_run(
+    db: &mongodb::Client,
+    blobs: &BlobServiceClient,
+) -> crate::util::Result<bool> {
+    let run = match db::claim_expired_run(db).await? {
+        Some(run) => run,
+        None => return Ok(false),
+    };
+
+    let deleted = db::delete_run_blobs(blobs, run.id)
+        .await
+        .map_err(|e| e.to_string());
+    if let Err(e) = deleted {
+        db::unexpire_run(db, run.id).await?;
+        Err(format!(
+            "could not delete outputs of run {}: {}",
+            &run.id, e
+        ))?
+    }
+
+    log::info!("deleted outputs of expired run {}", &run.id);
+    Ok(true)
+}
@@ -0,0 +1,314 @@
+use std::collections::{BTreeSet, HashSet};
+
+use serde::Serialize;
+
+use crate::db;
+use crate::models::Template;
+use crate::util::Result;
+
+/// The largest width or height a canvas may have, which keeps a single output under 256 MiB.
+pub const MAX_CANVAS_DIMENSION: u32 = 8192;
+
+/// Something wrong with a template, located by a JSON pointer into the submitted document.
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub pointer: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValidationReport {
+    /// Problems which would make a run of the template fail.
+    pub errors: Vec<Problem>,
+    /// Things which are likely mistakes but do not stop a run, such as layers which can never be
+    /// seen. Only looked for once a template has no errors.
+    pub warnings: Vec<Problem>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn error(&mut self, pointer: String, message: impl ToString) {
+        self.errors.push(Problem {
+            pointer,
+            message: message.to_string(),
+        });
+    }
+}
+
+/// Checks a template for everything that can be known to go wrong before rendering it.
+pub async fn validate_template(
+    db: &mongodb::Client,
+    template: &Template,
+) -> Result<ValidationReport> {
+    let (mut report, packs) = check_template(template);
+
+    let slugs: Vec<_> = packs.iter().map(|(slug, _)| slug.clone()).collect();
+    let existing = db::find_existing_packs(db, &slugs).await?;
+    for (slug, pointer) in packs {
+        if !existing.contains(&slug) {
+            report.error(pointer, format!("unknown pack {}", slug));
+        }
+    }
+
+    report.errors.sort_by(|a, b| a.pointer.cmp(&b.pointer));
+    Ok(report)
+}
+
+/// Checks everything about a template which can be known without looking up its packs, returning
+/// the packs it references along with where each was first referenced from.
+fn check_template(template: &Template) -> (ValidationReport, Vec<(String, String)>) {
+    let mut report = ValidationReport::default();
+    // Every pack referenced, with where it was first referenced from
+    let mut packs: Vec<(String, String)> = Vec::new();
+
+    let (width, height) = template.canvas_size;
+    for (i, size) in [width, height].into_iter().enumerate() {
+        let pointer = format!("/canvas_size/{}", i);
+        if size == 0 {
+            report.error(pointer, "canvas size must not be zero");
+        } else if size > MAX_CANVAS_DIMENSION {
+            report.error(
+                pointer,
+                format!("canvas size must be at most {}", MAX_CANVAS_DIMENSION),
+            );
+        }
+    }
+
+    // Sorted so the report is in a stable order
+    let aliases: BTreeSet<_> = template.aliases.keys().collect();
+    for alias in &aliases {
+        let pointer = format!("/aliases/{}", escape(alias));
+        if !alias.starts_with('$') || alias.len() < 2 {
+            report.error(pointer.clone(), "alias names must start with $");
+        } else if alias[1..].bytes().all(|b| b.is_ascii_digit()) {
+            // Outputs record bindings by alias name, which must not be mixed up with these
+            report.error(
+                pointer.clone(),
+                "aliases named $ and a number are reserved for layers using a reference directly",
+            );
+        }
+        for (i, reference) in template.aliases[*alias].iter().enumerate() {
+            check_ref(
+                &mut report,
+                &mut packs,
+                format!("{}/{}", pointer, i),
+                reference,
+            );
+        }
+    }
+    if !template.aliases.contains_key("$fg") {
+        report.error(
+            "/aliases".to_string(),
+            "template has no $fg alias to name outputs after",
+        );
+    }
+
+    if template.layers.is_empty() {
+        report.error("/layers".to_string(), "template has no layers");
+    }
+    let mut used = HashSet::new();
+    let mut names = HashSet::new();
+    for (i, layer) in template.layers.iter().enumerate() {
+        let pointer = format!("/layers/{}", i);
+        if let Some(name) = &layer.name {
+            if !names.insert(name) {
+                report.error(
+                    format!("{}/name", pointer),
+                    format!("duplicate layer name {}", name),
+                );
+            }
+        }
+        if layer.reference.starts_with('$') {
+            if template.aliases.contains_key(&layer.reference) {
+                used.insert(&layer.reference);
+            } else {
+                report.error(
+                    format!("{}/use", pointer),
+                    format!("undefined alias {}", &layer.reference),
+                );
+            }
+        } else {
+            check_ref(
+                &mut report,
+                &mut packs,
+                format!("{}/use", pointer),
+                &layer.reference,
+            );
+        }
+
+        let opacity = layer.opacity.0;
+        if !(0.0..=1.0).contains(&opacity) {
+            report.error(
+                format!("{}/opacity", pointer),
+                "opacity must be between 0 and 1",
+            );
+        }
+        let scale = layer.transform.scale.0;
+        if !(scale.is_finite() && scale > 0.0) {
+            report.error(
+                format!("{}/transform/scale", pointer),
+                "scale must be a positive number",
+            );
+        }
+        if !layer.transform.rotate.0.is_finite() {
+            report.error(
+                format!("{}/transform/rotate", pointer),
+                "rotation must be a finite number",
+            );
+        }
+    }
+    for alias in aliases {
+        // $fg names the outputs, so it does not need to be drawn
+        if alias != "$fg" && !used.contains(alias) {
+            report.error(
+                format!("/aliases/{}", escape(alias)),
+                format!("alias {} is not used by any layer", alias),
+            );
+        }
+    }
+
+    (report, packs)
+}
+
+/// Checks a `slug:glob` reference, remembering its pack so it can be looked up afterwards.
+fn check_ref(
+    report: &mut ValidationReport,
+    packs: &mut Vec<(String, String)>,
+    pointer: String,
+    reference: &str,
+) {
+    let (slug, glob) = match reference.split_once(':') {
+        Some((slug, glob)) if !slug.is_empty() => (slug, glob),
+        _ => {
+            let message = format!("reference is missing pack slug: {}", reference);
+            return report.error(pointer, message);
+        }
+    };
+    if let Err(e) = globset::Glob::new(glob) {
+        report.error(pointer.clone(), format!("invalid glob: {}", e));
+    }
+    if !packs.iter().any(|(known, _)| known == slug) {
+        packs.push((slug.to_string(), pointer));
+    }
+}
+
+/// Escapes a key for use as a JSON pointer segment, as in RFC 6901.
+pub fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn check(template: serde_json::Value) -> (Vec<String>, Vec<(String, String)>) {
+        let template = serde_json::from_value(template).unwrap();
+        let (report, packs) = check_template(&template);
+        let errors = report
+            .errors
+            .into_iter()
+            .map(|problem| format!("{}: {}", problem.pointer, problem.message))
+            .collect();
+        (errors, packs)
+    }
+
+    #[test]
+    fn valid_template_has_no_errors() {
+        let (errors, packs) = check(json!({
+            "aliases": { "$fg": ["cards:fg/*.png"], "$bg": ["backs:*.png"] },
+            "layers": [
+                { "use": "$bg" },
+                { "use": "cards:frame.png", "opacity": 0.5 },
+            ],
+            "canvas_size": [100, 100],
+        }));
+        assert_eq!(errors, Vec::<String>::new());
+        // Each pack once, where it was first referenced
+        assert_eq!(
+            packs,
+            vec![
+                ("backs".to_string(), "/aliases/$bg/0".to_string()),
+                ("cards".to_string(), "/aliases/$fg/0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn aliases_named_like_auto_aliases_are_reserved() {
+        let (errors, _) = check(json!({
+            "aliases": { "$fg": ["cards:*.png"], "$0": ["cards:a.png"], "$0a": ["cards:b.png"] },
+            "layers": [{ "use": "$0" }, { "use": "$0a" }],
+            "canvas_size": [100, 100],
+        }));
+        assert_eq!(
+            errors,
+            vec![
+                "/aliases/$0: aliases named $ and a number are reserved for layers using a \
+                 reference directly"
+            ]
+        );
+    }
+
+    #[test]
+    fn problems_are_located_by_pointer() {
+        let (mut errors, _) = check(json!({
+            "aliases": { "a/b": ["no-slug"], "$fg": [":*.png"], "$unused": ["cards:[*.png"] },
+            "layers": [
+                { "name": "top", "use": "$missing", "opacity": 2.0 },
+                { "name": "top", "use": "cards:a.png", "transform": { "scale": 0.0 } },
+            ],
+            "canvas_size": [0, 100000],
+        }));
+        let mut expected = vec![
+            "/aliases/$fg/0: reference is missing pack slug: :*.png".to_string(),
+            "/aliases/$unused: alias $unused is not used by any layer".to_string(),
+            "/aliases/a~1b: alias names must start with $".to_string(),
+            "/aliases/a~1b: alias a/b is not used by any layer".to_string(),
+            "/aliases/a~1b/0: reference is missing pack slug: no-slug".to_string(),
+            "/canvas_size/0: canvas size must not be zero".to_string(),
+            format!(
+                "/canvas_size/1: canvas size must be at most {}",
+                MAX_CANVAS_DIMENSION
+            ),
+            "/layers/0/opacity: opacity must be between 0 and 1".to_string(),
+            "/layers/0/use: undefined alias $missing".to_string(),
+            "/layers/1/name: duplicate layer name top".to_string(),
+            "/layers/1/transform/scale: scale must be a positive number".to_string(),
+        ];
+        // The glob error's wording comes from globset
+        let glob = errors
+            .iter()
+            .position(|e| e.starts_with("/aliases/$unused/0: invalid glob"))
+            .unwrap();
+        errors.remove(glob);
+        errors.sort();
+        expected.sort();
+        assert_eq!(errors, expected);
+    }
+
+    #[test]
+    fn template_needs_fg_and_layers() {
+        let (errors, _) = check(json!({
+            "aliases": {},
+            "layers": [],
+            "canvas_size": [100, 100],
+        }));
+        assert_eq!(
+            errors,
+            vec![
+                "/aliases: template has no $fg alias to name outputs after",
+                "/layers: template has no layers",
+            ]
+        );
+    }
+
+    #[test]
+    fn escape_follows_json_pointer() {
+        assert_eq!(escape("a/b~c"), "a~1b~0c");
+    }
+}
@@ -0,0 +1,355 @@
+use std::time::{Duration as StdDuration, Instant};
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use time::{Duration, OffsetDateTime};
+
+use crate::db::{
+    self, CompositorRun, CompositorRunStatus, RunProgress, WebhookDelivery, WebhookStatus,
+};
+use crate::models::Callback;
+
+/// How long a claimed delivery is held before another dispatcher may retry it.
+const DELIVERY_LEASE: Duration = Duration::seconds(60);
+const REQUEST_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+const MAX_ATTEMPTS: u32 = 6;
+const RETRY_BACKOFF: Duration = Duration::seconds(15);
+
+pub const SIGNATURE_HEADER: &str = "X-Blueprint-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Blueprint-Timestamp";
+
+/// The body POSTed to a run's callback URL once it finishes.
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload {
+    pub run_id: String,
+    pub status: CompositorRunStatus,
+    pub progress: RunProgress,
+    pub links: WebhookLinks,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookLinks {
+    pub run: String,
+    pub zip: String,
+}
+
+/// Sends signed webhook requests.
+#[derive(Debug, Clone)]
+pub struct WebhookSender {
+    client: reqwest::Client,
+}
+
+impl WebhookSender {
+    pub fn new() -> crate::util::Result<WebhookSender> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(WebhookSender { client })
+    }
+
+    /// POSTs the payload to the callback, returning the status code it responded with.
+    ///
+    /// If the callback has a secret, the request carries a `sha256=<hex>` HMAC of
+    /// `{timestamp}.{body}` in [`SIGNATURE_HEADER`], where the timestamp is sent in
+    /// [`TIMESTAMP_HEADER`] as unix seconds.
+    pub async fn send(
+        &self,
+        callback: &Callback,
+        payload: &WebhookPayload,
+    ) -> crate::util::Result<reqwest::StatusCode> {
+        let body = serde_json::to_vec(payload)?;
+        let timestamp = OffsetDateTime::now_utc().unix_timestamp().to_string();
+
+        let mut request = self
+            .client
+            .post(&callback.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, &timestamp);
+        if let Some(secret) = &callback.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, &timestamp, &body)?);
+        }
+
+        let response = request.body(body).send().await?;
+        Ok(response.status())
+    }
+}
+
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> crate::util::Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    Ok(format!(
+        "sha256={}",
+        hex::encode(mac.finalize().into_bytes())
+    ))
+}
+
+/// Delivers the webhooks of finished runs, retrying failed deliveries with backoff.
+pub struct WebhookDispatcher {
+    db: mongodb::Client,
+    sender: WebhookSender,
+    /// Used to build absolute links to a run's resources.
+    public_url: String,
+}
+
+impl WebhookDispatcher {
+    pub fn new(db: mongodb::Client, sender: WebhookSender, public_url: String) -> Self {
+        WebhookDispatcher {
+            db,
+            sender,
+            public_url,
+        }
+    }
+
+    pub async fn run(self) {
+        loop {
+            let idle = match self.poll().await {
+                Ok(delivered) => !delivered,
+                Err(e) => {
+                    log::error!("failed to poll for webhooks: {}", e);
+                    true
+                }
+            };
+            if idle {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    /// Attempts a single due delivery, returning `false` if there was none.
+    async fn poll(&self) -> crate::util::Result<bool> {
+        let run = match db::claim_webhook(&self.db, DELIVERY_LEASE).await? {
+            Some(run) => run,
+            None => return Ok(false),
+        };
+        let (callback, webhook) = match (&run.callback, &run.webhook) {
+            (Some(callback), Some(webhook)) => (callback, webhook),
+            _ => return Ok(true),
+        };
+
+        let started = Instant::now();
+        let attempted = OffsetDateTime::now_utc();
+        let result = self
+            .sender
+            .send(callback, &self.payload(&run))
+            .await
+            .map_err(|e| e.to_string());
+
+        let (status_code, error) = match result {
+            Ok(code) if code.is_success() => (Some(code.as_u16()), None),
+            Ok(code) => (
+                Some(code.as_u16()),
+                Some(format!("endpoint responded {}", code)),
+            ),
+            Err(e) => (None, Some(e)),
+        };
+        let delivery = WebhookDelivery {
+            attempted: attempted.into(),
+            duration_ms: started.elapsed().as_millis() as u64,
+            status_code,
+            error,
+        };
+
+        let (status, next_attempt) =
+            after_attempt(delivery.error.is_none(), webhook.attempts, attempted);
+        if let Some(e) = &delivery.error {
+            log::warn!(
+                "webhook for run {} failed (attempt {}): {}",
+                &run.id,
+                webhook.attempts,
+                e
+            );
+        }
+
+        db::record_webhook_delivery(&self.db, run.id, &delivery, status, next_attempt).await?;
+        Ok(true)
+    }
+
+    fn payload(&self, run: &CompositorRun) -> WebhookPayload {
+        let run_url = format!("{}/v1/runs/{}", self.public_url, run.id.to_hex());
+        WebhookPayload {
+            run_id: run.id.to_hex(),
+            status: run.status,
+            progress: run.progress,
+            links: WebhookLinks {
+                zip: format!("{}/zip", &run_url),
+                run: run_url,
+            },
+        }
+    }
+}
+
+/// Decides what becomes of a webhook after its `attempts`th delivery attempt, returning its new
+/// status and when it may next be attempted.
+fn after_attempt(
+    delivered: bool,
+    attempts: u32,
+    attempted: OffsetDateTime,
+) -> (WebhookStatus, OffsetDateTime) {
+    match delivered {
+        true => (WebhookStatus::Delivered, attempted),
+        false if attempts >= MAX_ATTEMPTS => (WebhookStatus::Failed, attempted),
+        false => {
+            let backoff = RETRY_BACKOFF * 2_i32.pow(attempts.saturating_sub(1));
+            (WebhookStatus::Pending, attempted + backoff)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    /// A request received by [`serve_once`], with lowercased header names.
+    struct Received {
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            let name = name.to_ascii_lowercase();
+            self.headers
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// Starts a local stand-in for a webhook endpoint which answers a single request with 200,
+    /// returning its URL and the request once it has been received.
+    fn serve_once() -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut headers = Vec::new();
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(':') {
+                    Some((key, value)) => {
+                        headers.push((key.trim().to_ascii_lowercase(), value.trim().to_string()))
+                    }
+                    None => break,
+                }
+            }
+            let length = headers
+                .iter()
+                .find(|(key, _)| key == "content-length")
+                .map_or(0, |(_, value)| value.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .unwrap();
+            sender.send(Received { headers, body }).unwrap();
+        });
+        (url, receiver)
+    }
+
+    fn payload() -> WebhookPayload {
+        WebhookPayload {
+            run_id: "6500000000000000000000aa".to_string(),
+            status: CompositorRunStatus::Succeeded,
+            progress: RunProgress::default(),
+            links: WebhookLinks {
+                run: "http://blueprint.test/v1/runs/6500000000000000000000aa".to_string(),
+                zip: "http://blueprint.test/v1/runs/6500000000000000000000aa/zip".to_string(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn send_signs_timestamp_and_body() {
+        let (url, requests) = serve_once();
+        let callback = Callback {
+            url,
+            secret: Some("hunter2".to_string()),
+        };
+        let payload = payload();
+
+        let status = WebhookSender::new()
+            .unwrap()
+            .send(&callback, &payload)
+            .await
+            .unwrap();
+        assert_eq!(status, reqwest::StatusCode::OK);
+
+        let request = requests.recv().unwrap();
+        assert_eq!(request.body, serde_json::to_vec(&payload).unwrap());
+        assert_eq!(request.header("content-type"), Some("application/json"));
+
+        let timestamp = request.header(TIMESTAMP_HEADER).unwrap();
+        let sent = timestamp.parse::<i64>().unwrap();
+        assert!((OffsetDateTime::now_utc().unix_timestamp() - sent).abs() < 60);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"hunter2").unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(&request.body);
+        let expected = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        assert_eq!(request.header(SIGNATURE_HEADER), Some(expected.as_str()));
+    }
+
+    #[tokio::test]
+    async fn send_without_secret_is_unsigned() {
+        let (url, requests) = serve_once();
+        let callback = Callback { url, secret: None };
+
+        WebhookSender::new()
+            .unwrap()
+            .send(&callback, &payload())
+            .await
+            .unwrap();
+
+        let request = requests.recv().unwrap();
+        assert!(request.header(TIMESTAMP_HEADER).is_some());
+        assert_eq!(request.header(SIGNATURE_HEADER), None);
+    }
+
+    #[test]
+    fn delivered_webhooks_are_not_retried() {
+        let attempted = OffsetDateTime::now_utc();
+        assert_eq!(
+            after_attempt(true, 1, attempted),
+            (WebhookStatus::Delivered, attempted)
+        );
+    }
+
+    #[test]
+    fn failed_webhooks_are_retried_with_doubling_backoff() {
+        let attempted = OffsetDateTime::now_utc();
+        for (attempts, backoff) in [(1, 15), (2, 30), (3, 60), (MAX_ATTEMPTS - 1, 240)] {
+            assert_eq!(
+                after_attempt(false, attempts, attempted),
+                (
+                    WebhookStatus::Pending,
+                    attempted + Duration::seconds(backoff)
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn webhooks_fail_after_the_last_attempt() {
+        let attempted = OffsetDateTime::now_utc();
+        assert_eq!(
+            after_attempt(false, MAX_ATTEMPTS, attempted),
+            (WebhookStatus::Failed, attempted)
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::time::Duration as StdDuration;
+
+use futures::future::{self, Either};
+
+use time::{Duration, OffsetDateTime};
+
+use crate::db::{self, CompositorRunStatus};
+
+use super::compositor::Compositor;
+
+/// How long a claimed run stays leased to a worker without a heartbeat.
+const LEASE_DURATION: Duration = Duration::seconds(60);
+const HEARTBEAT_INTERVAL: StdDuration = StdDuration::from_secs(20);
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(2);
+const SWEEP_INTERVAL: StdDuration = StdDuration::from_secs(30);
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::seconds(30);
+
+/// Pulls runs off the queue in the `runs` collection and renders them.
+pub struct Worker {
+    id: String,
+    db: mongodb::Client,
+    compositor: Compositor,
+}
+
+impl Worker {
+    pub fn new(id: String, db: mongodb::Client, compositor: Compositor) -> Worker {
+        Worker { id, db, compositor }
+    }
+
+    pub async fn run(self) {
+        loop {
+            let idle = match self.poll().await {
+                Ok(claimed) => !claimed,
+                Err(e) => {
+                    log::error!("worker {} failed to poll for runs: {}", &self.id, e);
+                    true
+                }
+            };
+            if idle {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    /// Claims and processes a single run, returning `false` if the queue was empty.
+    async fn poll(&self) -> crate::util::Result<bool> {
+        let run = match db::claim_run(&self.db, &self.id, LEASE_DURATION).await? {
+            Some(run) => run,
+            None => return Ok(false),
+        };
+        let run_id = run.id;
+        let events = self.compositor.events();
+        events.status(run_id, CompositorRunStatus::Running);
+        log::info!(
+            "worker {} claimed template run {} (attempt {})",
+            &self.id,
+            &run_id,
+            run.attempts
+        );
+
+        let heartbeat = {
+            let db = self.db.clone();
+            let worker = self.id.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    match db::renew_lease(&db, run_id, &worker, LEASE_DURATION).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            log::warn!("worker {} lost its lease on run {}", &worker, &run_id);
+                            break;
+                        }
+                        Err(e) => log::warn!("failed to renew lease on run {}: {}", &run_id, e),
+                    }
+                }
+            })
+        };
+
+        // The heartbeat only ends once the lease is lost, in which case the run may already have
+        // been handed to another worker, so rendering stops right away
+        let attempts = run.attempts;
+        let render = Box::pin(self.compositor.run_template(run));
+        let result = match future::select(render, heartbeat).await {
+            Either::Left((result, heartbeat)) => {
+                heartbeat.abort();
+                result.map_err(|e| e.to_string())
+            }
+            Either::Right(_) => {
+                log::warn!("worker {} stopped template run {}", &self.id, &run_id);
+                return Ok(true);
+            }
+        };
+
+        match result {
+            Ok(_) => log::info!("template run {} finished", &run_id),
+            Err(e) if attempts < MAX_ATTEMPTS => {
+                let backoff = RETRY_BACKOFF * 2_i32.pow(attempts - 1);
+                log::warn!(
+                    "template run {} failed, retrying in {}: {}",
+                    &run_id,
+                    backoff,
+                    &e
+                );
+                let next_attempt = OffsetDateTime::now_utc() + backoff;
+                db::requeue_run(&self.db, run_id, &self.id, &e, next_attempt).await?;
+                events.status(run_id, CompositorRunStatus::Queued);
+            }
+            Err(e) => {
+                log::error!("template run {} failed: {}", &run_id, &e);
+                db::fail_run(&self.db, run_id, &self.id, &e).await?;
+                events.status(run_id, CompositorRunStatus::Failed);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Periodically returns runs whose worker died mid-run to the queue.
+pub async fn requeue_expired_leases(db: mongodb::Client) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match db::requeue_expired_runs(&db, MAX_ATTEMPTS).await {
+            Ok(0) => {}
+            Ok(n) => log::warn!("requeued {} runs with expired leases", n),
+            Err(e) => log::error!("failed to requeue expired runs: {}", e),
+        }
+    }
+}
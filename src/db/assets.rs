@@ -1,23 +1,20 @@
-use std::io::Read;
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
 
 use crate::util::Result;
 use actix_multipart::form::tempfile::TempFile;
-use azure_storage_blobs::prelude::BlobServiceClient;
+use azure_storage_blobs::{blob::Blob, prelude::BlobServiceClient};
+use futures::{StreamExt, TryStreamExt};
 use mongodb::bson::doc;
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 
-use super::{get_entities, DateTime};
+use super::{get_entities, DateTime, PAGE_SIZE};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Asset {
-    #[serde(rename = "PartitionKey")]
-    pub partition_key: String,
-    #[serde(rename = "RowKey")]
-    pub row_key: String,
-    pub slug: String,
-    pub file_name: String,
-    pub content_type: Option<String>,
-}
+/// How much of an asset is downloaded to read its dimensions from, which covers the headers of
+/// common image formats.
+const DIMENSIONS_PREFIX: u64 = 64 * 1024;
+const DIMENSIONS_CONCURRENCY: usize = 8;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AssetPack {
@@ -63,6 +60,21 @@ pub async fn get_packs(
     get_entities::<AssetPack, crate::models::AssetPack>(client, "packs", page).await
 }
 
+/// Returns which of `slugs` belong to existing packs.
+pub async fn find_existing_packs(
+    client: &mongodb::Client,
+    slugs: &[String],
+) -> Result<HashSet<String>> {
+    let cursor = client
+        .default_database()
+        .unwrap()
+        .collection::<AssetPack>("packs")
+        .find(doc! { "_id": { "$in": slugs } }, None)
+        .await?;
+    let packs: Vec<AssetPack> = cursor.try_collect().await?;
+    Ok(packs.into_iter().map(|pack| pack.slug).collect())
+}
+
 pub async fn create_pack(
     db: &mongodb::Client,
     blobs: &BlobServiceClient,
@@ -95,11 +107,113 @@ pub async fn create_pack(
     Ok(())
 }
 
+/// Lists the blobs of a pack whose paths match a glob, in order of their paths.
+pub async fn find_pack_blobs(
+    blobs: &BlobServiceClient,
+    pack_slug: &str,
+    glob: &str,
+) -> Result<Vec<Blob>> {
+    let matcher = globset::Glob::new(glob)?.compile_matcher();
+    let mut pages = blobs
+        .container_client(format!("pack-{}", pack_slug))
+        .list_blobs()
+        .into_stream();
+
+    let mut results = Vec::new();
+    while let Some(page) = pages.try_next().await? {
+        results.extend(
+            page.blobs
+                .blobs()
+                .filter(|blob| matcher.is_match(&blob.name))
+                .cloned(),
+        );
+    }
+
+    Ok(results)
+}
+
+/// Returns a page of the assets of a pack whose paths match a glob.
+pub async fn get_pack_assets(
+    blobs: &BlobServiceClient,
+    pack_slug: &str,
+    glob: &str,
+    page: usize,
+) -> Result<Vec<crate::models::PackAsset>> {
+    let page_size = PAGE_SIZE as usize;
+    let matching = find_pack_blobs(blobs, pack_slug, glob).await?;
+    let blobs_on_page = matching
+        .into_iter()
+        .skip(page.saturating_sub(1) * page_size)
+        .take(page_size);
+
+    let container = blobs.container_client(format!("pack-{}", pack_slug));
+    let assets = futures::stream::iter(blobs_on_page)
+        .map(|blob| {
+            let client = container.blob_client(&blob.name);
+            async move {
+                let size = blob.properties.content_length;
+                // Ranges starting past the end of a blob can't be satisfied
+                let dimensions = match size {
+                    0 => None,
+                    _ => {
+                        let mut prefix = Vec::new();
+                        let mut chunks = client.get().range(0..DIMENSIONS_PREFIX).into_stream();
+                        while let Some(chunk) = chunks.try_next().await? {
+                            prefix.extend(chunk.data.collect().await?);
+                        }
+                        image::io::Reader::new(Cursor::new(prefix))
+                            .with_guessed_format()?
+                            .into_dimensions()
+                            .ok()
+                    }
+                };
+                Ok::<_, azure_core::Error>(crate::models::PackAsset {
+                    path: blob.name,
+                    size,
+                    content_type: blob.properties.content_type,
+                    dimensions,
+                    last_modified: blob.properties.last_modified.into(),
+                })
+            }
+        })
+        .buffered(DIMENSIONS_CONCURRENCY)
+        .try_collect()
+        .await?;
+
+    Ok(assets)
+}
+
 pub async fn upload_zipped_pack(
     blobs: &BlobServiceClient,
     file_metadata: TempFile,
     pack_slug: &str,
 ) -> crate::util::Result<()> {
+    merge_zipped_pack(blobs, file_metadata, pack_slug, ExistingAssets::Overwrite).await?;
+    Ok(())
+}
+
+/// What to do with files of a zip merged into a pack which the pack already has.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExistingAssets {
+    #[default]
+    Skip,
+    Overwrite,
+}
+
+/// Unpacks a zip into a pack alongside the files it already has.
+pub async fn merge_zipped_pack(
+    blobs: &BlobServiceClient,
+    file_metadata: TempFile,
+    pack_slug: &str,
+    existing_assets: ExistingAssets,
+) -> crate::util::Result<crate::models::MergedAssets> {
+    let existing: HashSet<String> = find_pack_blobs(blobs, pack_slug, "**")
+        .await?
+        .into_iter()
+        .map(|blob| blob.name)
+        .collect();
+    let mut merged = crate::models::MergedAssets::default();
     let mut zip = zip::ZipArchive::new(file_metadata.file)?;
 
     for index in 0..zip.len() {
@@ -116,20 +230,80 @@ pub async fn upload_zipped_pack(
             name.as_os_str().to_string_lossy().to_string()
         };
 
+        let replaced = existing.contains(&name);
+        if replaced && existing_assets == ExistingAssets::Skip {
+            merged.skipped.push(name);
+            continue;
+        }
         blobs
             .container_client(format!("pack-{}", pack_slug))
-            .blob_client(name)
+            .blob_client(&name)
             .put_block_blob(buf)
             .await?;
+        match replaced {
+            true => merged.replaced.push(name),
+            false => merged.added.push(name),
+        }
     }
 
-    Ok(())
+    Ok(merged)
+}
+
+/// Uploads a single file to a pack, returning whether it replaced one with the same path.
+pub async fn put_pack_asset(
+    blobs: &BlobServiceClient,
+    pack_slug: &str,
+    path: &str,
+    content: Vec<u8>,
+    content_type: Option<String>,
+) -> Result<bool> {
+    let blob = blobs
+        .container_client(format!("pack-{}", pack_slug))
+        .blob_client(path);
+    let replaced = blob.exists().await?;
+    let mut put = blob.put_block_blob(content);
+    if let Some(content_type) = content_type {
+        put = put.content_type(content_type);
+    }
+    put.await?;
+    Ok(replaced)
+}
+
+/// Deletes a single file from a pack, returning `false` if there was none at `path`.
+pub async fn delete_pack_asset(
+    blobs: &BlobServiceClient,
+    pack_slug: &str,
+    path: &str,
+) -> Result<bool> {
+    let blob = blobs
+        .container_client(format!("pack-{}", pack_slug))
+        .blob_client(path);
+    if !blob.exists().await? {
+        return Ok(false);
+    }
+    blob.delete().await?;
+    Ok(true)
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct GenericRowKeyEntity {
-    #[serde(rename = "RowKey")]
-    row_key: String,
+/// Marks a pack as modified now after a change to its files, also setting its version if one
+/// is given.
+pub async fn touch_pack(
+    db: &mongodb::Client,
+    pack_slug: &str,
+    version: Option<String>,
+) -> Result<()> {
+    let mut set = doc! { "last_modified": DateTime(OffsetDateTime::now_utc()) };
+    if let Some(version) = version {
+        set.insert("version", version);
+    }
+
+    db.default_database()
+        .unwrap()
+        .collection::<AssetPack>("packs")
+        .update_one(doc! { "_id": pack_slug }, doc! { "$set": set }, None)
+        .await?;
+
+    Ok(())
 }
 
 pub async fn delete_pack(
@@ -1,11 +1,13 @@
 mod users;
-use bson::doc;
+use bson::{doc, Bson, Document};
 use futures::StreamExt;
 pub use users::*;
 mod assets;
 pub use assets::*;
 mod runs;
 pub use runs::*;
+mod templates;
+pub use templates::*;
 
 use serde::{Deserialize, Serialize};
 use std::num::NonZeroU8;
@@ -15,10 +17,10 @@ use time::{
         iso8601::{self, TimePrecision},
         Iso8601,
     },
-    OffsetDateTime,
+    OffsetDateTime, UtcOffset,
 };
 
-const PAGE_SIZE: u32 = 25;
+pub(crate) const PAGE_SIZE: u32 = 25;
 const FORMAT: Iso8601<6651332276410551414894041209048662016> = Iso8601::<
     {
         iso8601::Config::DEFAULT
@@ -62,6 +64,15 @@ impl From<DateTime> for OffsetDateTime {
     }
 }
 
+/// Stored as the same fixed-width string as the serde representation, so dates can be compared
+/// in queries. Dates are converted to UTC first, which is what every stored date is in.
+impl From<DateTime> for Bson {
+    fn from(value: DateTime) -> Self {
+        let utc = value.0.to_offset(UtcOffset::UTC);
+        Bson::String(utc.format(&FORMAT).expect("datetime is always formattable"))
+    }
+}
+
 pub async fn get_entities<T, U>(
     client: &mongodb::Client,
     table_name: &str,
@@ -71,18 +82,34 @@ where
     T: for<'a> Deserialize<'a> + Send + Sync,
     U: From<T>,
 {
+    query_entities::<T, U>(client, table_name, doc! {}, None, page).await
+}
+
+/// Like [`get_entities`], but only returns entities matching `filter`, in the order of `sort`.
+pub async fn query_entities<T, U>(
+    client: &mongodb::Client,
+    table_name: &str,
+    filter: Document,
+    sort: Option<Document>,
+    page: usize,
+) -> crate::util::Result<Vec<U>>
+where
+    T: for<'a> Deserialize<'a> + Send + Sync,
+    U: From<T>,
+{
+    let mut pipeline = vec![doc! { "$match": filter }];
+    if let Some(sort) = sort {
+        pipeline.push(doc! { "$sort": sort });
+    }
     // Skip to the desired page in the stream
+    pipeline.push(doc! { "$skip": (page - 1) as u32 * PAGE_SIZE });
+    pipeline.push(doc! { "$limit": PAGE_SIZE });
+
     let cursor = client
         .default_database()
         .unwrap()
         .collection::<T>(table_name)
-        .aggregate(
-            [
-                doc! { "$skip": (page - 1) as u32 * PAGE_SIZE },
-                doc! { "$limit": PAGE_SIZE },
-            ],
-            None,
-        )
+        .aggregate(pipeline, None)
         .await?;
 
     let page: Vec<_> = cursor.with_type::<T>().collect().await;
@@ -1,7 +1,28 @@
-use bson::Bson;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use azure_storage_blobs::prelude::BlobServiceClient;
+use bson::{doc, oid::ObjectId, Bson};
+use futures::TryStreamExt;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
 use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+
+use crate::blueprint::image_cache::AssetRef;
+use crate::models::{Callback, RunFilter, RunOptions, Template};
+use crate::util::Result;
 
-use super::DateTime;
+use super::{query_entities, DateTime, TemplateRef};
+
+pub const RUNS_COLLECTION: &str = "runs";
+pub const RUN_OUTPUTS_COLLECTION: &str = "run_outputs";
+/// Only the first errors of a run are kept on its document; every failure is still counted in
+/// its progress and recorded on its output.
+const MAX_RECORDED_ERRORS: i32 = 100;
+/// Outputs are always encoded as PNG.
+pub const OUTPUT_CONTENT_TYPE: &str = "image/png";
+/// How long a run's outputs are kept unless the run overrides it or is pinned.
+pub const DEFAULT_RETENTION_DAYS: u32 = 30;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CompositorRun {
@@ -9,41 +30,913 @@ pub struct CompositorRun {
     pub id: bson::oid::ObjectId,
     pub created: DateTime,
     pub status: CompositorRunStatus,
+    pub template: Template,
+    /// The number of times a worker has claimed this run.
+    #[serde(default)]
+    pub attempts: u32,
+    /// The earliest time a worker may claim this run.
+    pub next_attempt: DateTime,
+    #[serde(default)]
+    pub lease_owner: Option<String>,
+    #[serde(default)]
+    pub lease_expires: Option<DateTime>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub progress: RunProgress,
+    #[serde(default)]
+    pub started: Option<DateTime>,
+    #[serde(default)]
+    pub finished: Option<DateTime>,
+    #[serde(default)]
+    pub errors: Vec<RunError>,
+    /// Whether the run's outputs should be deleted because it was cancelled.
+    #[serde(default)]
+    pub delete_outputs: bool,
+    #[serde(default)]
+    pub callback: Option<Callback>,
+    #[serde(default)]
+    pub webhook: Option<WebhookState>,
+    #[serde(default)]
+    pub submitted_by: Option<String>,
+    /// When the run's outputs become eligible for deletion.
+    #[serde(default)]
+    pub expires_at: Option<DateTime>,
+    /// Pinned runs are never expired.
+    #[serde(default)]
+    pub pinned: bool,
+    /// When the run's outputs were deleted for having expired.
+    #[serde(default)]
+    pub expired_at: Option<DateTime>,
+    /// The assets each alias expanded to when the run started.
+    #[serde(default)]
+    pub resolved_assets: Vec<ResolvedAlias>,
+    /// Assets to bind instead of expanding the template's aliases again.
+    #[serde(default)]
+    pub pinned_assets: Option<Vec<ResolvedAlias>>,
+    /// The run this one was submitted again from.
+    #[serde(default)]
+    pub rerun_of: Option<ObjectId>,
+    /// Reuse outputs of earlier runs which were rendered from the same inputs.
+    #[serde(default)]
+    pub incremental: bool,
+    /// The saved template the run was started from.
+    #[serde(default)]
+    pub saved_template: Option<TemplateRef>,
+}
+
+impl CompositorRun {
+    /// How many days the run's outputs are kept for, as given when it was submitted or later.
+    pub fn retention_days(&self) -> Option<u32> {
+        let expires_at = OffsetDateTime::from(self.expires_at?);
+        let days = (expires_at - OffsetDateTime::from(self.created)).whole_days();
+        u32::try_from(days).ok()
+    }
+}
+
+/// Delivery progress of the webhook sent when a run finishes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookState {
+    pub status: WebhookStatus,
+    pub attempts: u32,
+    /// The earliest time the next delivery may be attempted.
+    pub next_attempt: DateTime,
+    pub deliveries: Vec<WebhookDelivery>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl From<WebhookStatus> for Bson {
+    fn from(value: WebhookStatus) -> Self {
+        bson::to_bson(&value).expect("webhook status is always serializable")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookDelivery {
+    pub attempted: DateTime,
+    pub duration_ms: u64,
+    /// The HTTP status the endpoint responded with, if it responded at all.
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct RunProgress {
+    /// The number of combinations the run will render.
+    pub total: u64,
+    pub completed: u64,
+    pub failed: u64,
+    /// How many of the completed combinations were copied from earlier runs.
+    #[serde(default)]
+    pub reused: u64,
+}
+
+/// The part of a run an error happened in.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStage {
+    /// Resolving alias references to assets.
+    Expand,
+    /// Fetching, decoding and transforming a layer's asset.
+    Load,
+    /// Placing layers on the canvas.
+    Compose,
+    Encode,
+    Upload,
+    /// Anything outside of rendering, such as a worker dying or losing its database connection.
+    Worker,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunError {
+    pub stage: RunStage,
+    /// The alias bindings of the combination that failed, if the error is specific to one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bindings: Option<BTreeMap<String, String>>,
+    pub message: String,
+}
+
+impl RunError {
+    pub fn new(stage: RunStage, message: impl ToString) -> RunError {
+        RunError {
+            stage,
+            bindings: None,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// The assets an alias of a run's template was bound to.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResolvedAlias {
+    pub alias: String,
+    pub assets: Vec<AssetRef>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+/// The outcome of rendering a single combination of a run.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunOutput {
+    pub run_id: ObjectId,
+    /// The `pack:path` each alias was bound to.
+    pub bindings: BTreeMap<String, String>,
+    pub blob_name: Option<String>,
+    pub size: Option<u64>,
+    pub duration_ms: u64,
+    pub error: Option<RunError>,
+    pub created: DateTime,
+    /// Identifies the inputs the output was rendered from, see `compositor::cache_key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_key: Option<String>,
+    /// The run whose output was copied instead of rendering this one again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reused_from: Option<ObjectId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
 pub enum CompositorRunStatus {
+    Queued,
     Running,
     Succeeded,
     Failed,
+    Cancelled,
+    /// Finished, but some combinations failed to render.
+    CompletedWithErrors,
+}
+
+impl CompositorRunStatus {
+    /// The states of runs which will never be picked up by a worker again.
+    pub const TERMINAL: [CompositorRunStatus; 4] = [
+        CompositorRunStatus::Succeeded,
+        CompositorRunStatus::Failed,
+        CompositorRunStatus::Cancelled,
+        CompositorRunStatus::CompletedWithErrors,
+    ];
+
+    pub fn is_terminal(self) -> bool {
+        Self::TERMINAL.contains(&self)
+    }
+}
+
+/// The name the API gives the status.
+impl fmt::Display for CompositorRunStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CompositorRunStatus::Queued => "queued",
+            CompositorRunStatus::Running => "running",
+            CompositorRunStatus::Succeeded => "succeeded",
+            CompositorRunStatus::Failed => "failed",
+            CompositorRunStatus::Cancelled => "cancelled",
+            CompositorRunStatus::CompletedWithErrors => "completed_with_errors",
+        })
+    }
 }
 
 impl From<CompositorRunStatus> for Bson {
     fn from(value: CompositorRunStatus) -> Self {
-        match value {
-            CompositorRunStatus::Running => Bson::String("running".to_string()),
-            CompositorRunStatus::Succeeded => Bson::String("succeeded".to_string()),
-            CompositorRunStatus::Failed => Bson::String("failed".to_string()),
-        }
+        bson::to_bson(&value).expect("run status is always serializable")
     }
 }
 
 impl From<CompositorRun> for crate::models::CompositorRun {
     fn from(value: CompositorRun) -> Self {
         Self {
-            id: value.id.to_string(),
+            id: value.id.to_hex(),
             created: value.created,
             status: value.status,
+            attempts: value.attempts,
+            last_error: value.last_error,
+            progress: value.progress,
+            started: value.started,
+            finished: value.finished,
+            errors: value.errors,
+            callback_url: value.callback.map(|c| c.url),
+            webhook: value.webhook,
+            submitted_by: value.submitted_by,
+            expires_at: value.expires_at,
+            pinned: value.pinned,
+            expired_at: value.expired_at,
+            template: value.template,
+            resolved_assets: value.resolved_assets,
+            rerun_of: value.rerun_of.map(|id| id.to_hex()),
+            incremental: value.incremental,
+            saved_template: value.saved_template,
         }
     }
 }
 
-impl From<crate::models::CompositorRun> for CompositorRun {
-    fn from(value: crate::models::CompositorRun) -> Self {
+impl From<RunOutput> for crate::models::OutputFile {
+    fn from(value: RunOutput) -> Self {
+        let blob_name = value.blob_name.unwrap_or_default();
         Self {
-            id: Default::default(),
+            name: output_file_name(&blob_name).to_string(),
+            size: value.size.unwrap_or_default(),
+            content_type: OUTPUT_CONTENT_TYPE.to_string(),
+            bindings: value.bindings,
             created: value.created,
-            status: value.status,
         }
     }
 }
+
+/// Returns the name of an output within its run, e.g. `a/b.png` for `{run_id}/a/b.png`.
+pub fn output_file_name(blob_name: &str) -> &str {
+    blob_name
+        .split_once('/')
+        .map_or(blob_name, |(_, name)| name)
+}
+
+fn runs(client: &mongodb::Client) -> mongodb::Collection<CompositorRun> {
+    client
+        .default_database()
+        .unwrap()
+        .collection::<CompositorRun>(RUNS_COLLECTION)
+}
+
+fn run_outputs(client: &mongodb::Client) -> mongodb::Collection<RunOutput> {
+    client
+        .default_database()
+        .unwrap()
+        .collection::<RunOutput>(RUN_OUTPUTS_COLLECTION)
+}
+
+pub async fn find_run(client: &mongodb::Client, id: ObjectId) -> Result<Option<CompositorRun>> {
+    Ok(runs(client).find_one(doc! { "_id": id }, None).await?)
+}
+
+/// Lists runs matching the filter, newest first.
+pub async fn get_runs(
+    client: &mongodb::Client,
+    filter: RunFilter,
+    page: usize,
+) -> Result<Vec<crate::models::CompositorRun>> {
+    let mut query = doc! {};
+    if let Some(status) = filter.status {
+        query.insert("status", status);
+    }
+    if let Some(submitted_by) = filter.submitted_by {
+        query.insert("submitted_by", submitted_by);
+    }
+    let mut created = doc! {};
+    if let Some(after) = filter.created_after {
+        created.insert("$gte", DateTime::from(after));
+    }
+    if let Some(before) = filter.created_before {
+        created.insert("$lt", DateTime::from(before));
+    }
+    if !created.is_empty() {
+        query.insert("created", created);
+    }
+
+    let sort = doc! { "created": -1, "_id": -1 };
+    query_entities::<CompositorRun, crate::models::CompositorRun>(
+        client,
+        RUNS_COLLECTION,
+        query,
+        Some(sort),
+        page,
+    )
+    .await
+}
+
+pub async fn get_run(
+    client: &mongodb::Client,
+    id: &str,
+) -> Result<Option<crate::models::CompositorRun>> {
+    let id = match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return Ok(None),
+    };
+    let response = runs(client).find_one(doc! { "_id": id }, None).await?;
+    Ok(response.map(|r| r.into()))
+}
+
+/// Moves a run leased to `worker` to `to` if it is currently in one of the `from` states,
+/// returning whether it did.
+pub async fn transition_run(
+    client: &mongodb::Client,
+    id: ObjectId,
+    worker: &str,
+    from: &[CompositorRunStatus],
+    to: CompositorRunStatus,
+) -> Result<bool> {
+    let mut update = doc! { "$set": { "status": to } };
+    if to.is_terminal() {
+        let finished = DateTime::from(OffsetDateTime::now_utc());
+        update
+            .get_document_mut("$set")?
+            .insert("finished", finished);
+        update.insert("$unset", doc! { "lease_owner": "", "lease_expires": "" });
+    }
+    let result = runs(client)
+        .update_one(
+            doc! { "_id": id, "lease_owner": worker, "status": { "$in": from } },
+            update,
+            None,
+        )
+        .await?;
+    Ok(result.matched_count > 0)
+}
+
+/// Cancels a queued or running run, returning `false` if it had already finished.
+///
+/// A worker processing the run stops before its next combination.
+pub async fn cancel_run(
+    client: &mongodb::Client,
+    id: ObjectId,
+    delete_outputs: bool,
+) -> Result<bool> {
+    let result = runs(client)
+        .update_one(
+            doc! {
+                "_id": id,
+                "status": {
+                    "$in": [CompositorRunStatus::Queued, CompositorRunStatus::Running],
+                },
+            },
+            doc! {
+                "$set": {
+                    "status": CompositorRunStatus::Cancelled,
+                    "finished": DateTime::from(OffsetDateTime::now_utc()),
+                    "delete_outputs": delete_outputs,
+                },
+                "$unset": { "lease_owner": "", "lease_expires": "" },
+            },
+            None,
+        )
+        .await?;
+    Ok(result.matched_count > 0)
+}
+
+/// Deletes every output blob under `template-output/{run_id}/`.
+pub async fn delete_run_blobs(blobs: &BlobServiceClient, id: ObjectId) -> Result<()> {
+    let container = blobs.container_client("template-output");
+    let mut pages = container
+        .list_blobs()
+        .prefix(format!("{}/", id))
+        .into_stream();
+
+    while let Some(page) = pages.try_next().await? {
+        for blob in page.blobs.blobs() {
+            container.blob_client(&blob.name).delete().await?;
+        }
+    }
+    Ok(())
+}
+
+fn retention_deadline(created: OffsetDateTime, retention_days: Option<u32>) -> DateTime {
+    let days = retention_days.unwrap_or(DEFAULT_RETENTION_DAYS);
+    (created + Duration::days(days.into())).into()
+}
+
+/// Pins or unpins a run and overrides how long it is retained, returning `false` if there is no
+/// such run.
+pub async fn update_run_retention(
+    client: &mongodb::Client,
+    id: ObjectId,
+    pinned: Option<bool>,
+    retention_days: Option<u32>,
+) -> Result<bool> {
+    let run = match find_run(client, id).await? {
+        Some(run) => run,
+        None => return Ok(false),
+    };
+
+    let mut modifications = doc! {};
+    if let Some(pinned) = pinned {
+        modifications.insert("pinned", pinned);
+    }
+    if let Some(days) = retention_days {
+        let expires_at = retention_deadline(run.created.into(), Some(days));
+        modifications.insert("expires_at", expires_at);
+    }
+    if modifications.is_empty() {
+        return Ok(true);
+    }
+
+    runs(client)
+        .update_one(doc! { "_id": id }, doc! { "$set": modifications }, None)
+        .await?;
+    Ok(true)
+}
+
+/// Claims a finished, unpinned run whose retention has run out by marking it as expired.
+pub async fn claim_expired_run(client: &mongodb::Client) -> Result<Option<CompositorRun>> {
+    let now = DateTime::from(OffsetDateTime::now_utc());
+    let filter = doc! {
+        "status": { "$in": &CompositorRunStatus::TERMINAL[..] },
+        "expires_at": { "$lt": now },
+        "pinned": { "$ne": true },
+        "expired_at": null,
+    };
+    Ok(runs(client)
+        .find_one_and_update(filter, doc! { "$set": { "expired_at": now } }, None)
+        .await?)
+}
+
+/// Undoes [`claim_expired_run`] so the run is tried again later.
+pub async fn unexpire_run(client: &mongodb::Client, id: ObjectId) -> Result<()> {
+    runs(client)
+        .update_one(
+            doc! { "_id": id },
+            doc! { "$unset": { "expired_at": "" } },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Stores a new run in the queue, returning its id.
+pub async fn enqueue_run(
+    client: &mongodb::Client,
+    template: Template,
+    options: RunOptions,
+) -> Result<ObjectId> {
+    let now = OffsetDateTime::now_utc();
+    let webhook = options.callback.as_ref().map(|_| WebhookState {
+        status: WebhookStatus::Pending,
+        attempts: 0,
+        next_attempt: now.into(),
+        deliveries: Vec::new(),
+    });
+    let run = CompositorRun {
+        id: Default::default(),
+        created: now.into(),
+        status: CompositorRunStatus::Queued,
+        template,
+        attempts: 0,
+        next_attempt: now.into(),
+        lease_owner: None,
+        lease_expires: None,
+        last_error: None,
+        progress: Default::default(),
+        started: None,
+        finished: None,
+        errors: Vec::new(),
+        delete_outputs: false,
+        callback: options.callback,
+        webhook,
+        submitted_by: options.submitted_by,
+        expires_at: Some(retention_deadline(now, options.retention_days)),
+        pinned: false,
+        expired_at: None,
+        resolved_assets: Vec::new(),
+        pinned_assets: options.pinned_assets,
+        rerun_of: options.rerun_of,
+        incremental: options.incremental,
+        saved_template: options.saved_template,
+    };
+
+    let result = runs(client).insert_one(&run, None).await?;
+    let id = result
+        .inserted_id
+        .as_object_id()
+        .ok_or("run was inserted without an object id")?;
+    Ok(id)
+}
+
+/// Atomically claims the oldest queued run which is due, leasing it to `worker` for `lease`.
+pub async fn claim_run(
+    client: &mongodb::Client,
+    worker: &str,
+    lease: Duration,
+) -> Result<Option<CompositorRun>> {
+    let now = OffsetDateTime::now_utc();
+    let filter = doc! {
+        "status": CompositorRunStatus::Queued,
+        "next_attempt": { "$lte": DateTime::from(now) },
+    };
+    let update = doc! {
+        "$set": {
+            "status": CompositorRunStatus::Running,
+            "lease_owner": worker,
+            "lease_expires": DateTime::from(now + lease),
+        },
+        "$inc": { "attempts": 1 },
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! { "next_attempt": 1 })
+        .return_document(ReturnDocument::After)
+        .build();
+
+    Ok(runs(client)
+        .find_one_and_update(filter, update, options)
+        .await?)
+}
+
+/// Extends the lease on a run, returning `false` if `worker` no longer holds it.
+pub async fn renew_lease(
+    client: &mongodb::Client,
+    id: ObjectId,
+    worker: &str,
+    lease: Duration,
+) -> Result<bool> {
+    let result = runs(client)
+        .update_one(
+            doc! {
+                "_id": id,
+                "status": CompositorRunStatus::Running,
+                "lease_owner": worker,
+            },
+            doc! {
+                "$set": {
+                    "lease_expires": DateTime::from(OffsetDateTime::now_utc() + lease),
+                }
+            },
+            None,
+        )
+        .await?;
+    Ok(result.matched_count > 0)
+}
+
+/// Puts a run held by `worker` back on the queue after a failed attempt.
+pub async fn requeue_run(
+    client: &mongodb::Client,
+    id: ObjectId,
+    worker: &str,
+    error: &str,
+    next_attempt: OffsetDateTime,
+) -> Result<()> {
+    runs(client)
+        .update_one(
+            doc! {
+                "_id": id,
+                "status": CompositorRunStatus::Running,
+                "lease_owner": worker,
+            },
+            doc! {
+                "$set": {
+                    "status": CompositorRunStatus::Queued,
+                    "next_attempt": DateTime::from(next_attempt),
+                    "last_error": error,
+                },
+                "$unset": { "lease_owner": "", "lease_expires": "" },
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Marks a run held by `worker` as failed for good.
+pub async fn fail_run(
+    client: &mongodb::Client,
+    id: ObjectId,
+    worker: &str,
+    error: &str,
+) -> Result<()> {
+    let run_error = bson::to_bson(&RunError::new(RunStage::Worker, error))?;
+    runs(client)
+        .update_one(
+            doc! {
+                "_id": id,
+                "status": CompositorRunStatus::Running,
+                "lease_owner": worker,
+            },
+            doc! {
+                "$set": {
+                    "status": CompositorRunStatus::Failed,
+                    "last_error": error,
+                    "finished": DateTime::from(OffsetDateTime::now_utc()),
+                },
+                "$unset": { "lease_owner": "", "lease_expires": "" },
+                "$push": {
+                    "errors": { "$each": [run_error], "$slice": MAX_RECORDED_ERRORS },
+                },
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Requeues running jobs whose worker stopped renewing its lease, or fails them if they have
+/// used up their attempts. Returns the number of runs that were requeued.
+pub async fn requeue_expired_runs(client: &mongodb::Client, max_attempts: u32) -> Result<u64> {
+    let now = DateTime::from(OffsetDateTime::now_utc());
+    let expired = doc! {
+        "status": CompositorRunStatus::Running,
+        "lease_expires": { "$lt": now },
+    };
+
+    let run_error = bson::to_bson(&RunError::new(RunStage::Worker, "worker lease expired"))?;
+    let mut exhausted = expired.clone();
+    exhausted.insert("attempts", doc! { "$gte": max_attempts });
+    runs(client)
+        .update_many(
+            exhausted,
+            doc! {
+                "$set": {
+                    "status": CompositorRunStatus::Failed,
+                    "last_error": "worker lease expired",
+                    "finished": now,
+                },
+                "$unset": { "lease_owner": "", "lease_expires": "" },
+                "$push": {
+                    "errors": { "$each": [run_error], "$slice": MAX_RECORDED_ERRORS },
+                },
+            },
+            None,
+        )
+        .await?;
+
+    let result = runs(client)
+        .update_many(
+            expired,
+            doc! {
+                "$set": {
+                    "status": CompositorRunStatus::Queued,
+                    "next_attempt": now,
+                    "last_error": "worker lease expired",
+                },
+                "$unset": { "lease_owner": "", "lease_expires": "" },
+            },
+            None,
+        )
+        .await?;
+    Ok(result.modified_count)
+}
+
+/// Resets a run's progress at the start of an attempt, discarding outputs from earlier attempts.
+/// Returns `false` if the run is no longer leased to `worker`.
+pub async fn start_run(
+    client: &mongodb::Client,
+    id: ObjectId,
+    worker: &str,
+    total: u64,
+    resolved_assets: &[ResolvedAlias],
+) -> Result<bool> {
+    let result = runs(client)
+        .update_one(
+            doc! {
+                "_id": id,
+                "status": CompositorRunStatus::Running,
+                "lease_owner": worker,
+            },
+            doc! {
+                "$set": {
+                    "started": DateTime::from(OffsetDateTime::now_utc()),
+                    "progress": {
+                        "total": total as i64,
+                        "completed": 0,
+                        "failed": 0,
+                        "reused": 0,
+                    },
+                    "errors": [],
+                    "resolved_assets": bson::to_bson(resolved_assets)?,
+                },
+                "$unset": { "finished": "" },
+            },
+            None,
+        )
+        .await?;
+    if result.matched_count == 0 {
+        return Ok(false);
+    }
+
+    run_outputs(client)
+        .delete_many(doc! { "run_id": id }, None)
+        .await?;
+    Ok(true)
+}
+
+/// Records a rendered combination and counts it towards the run's progress, returning `false` if
+/// the run is no longer running or no longer leased to `worker`.
+pub async fn record_output(
+    client: &mongodb::Client,
+    worker: &str,
+    output: &RunOutput,
+) -> Result<bool> {
+    let mut counters = match output.error {
+        Some(_) => doc! { "progress.failed": 1 },
+        None => doc! { "progress.completed": 1 },
+    };
+    if output.reused_from.is_some() {
+        counters.insert("progress.reused", 1);
+    }
+    let result = runs(client)
+        .update_one(
+            doc! {
+                "_id": output.run_id,
+                "status": CompositorRunStatus::Running,
+                "lease_owner": worker,
+            },
+            doc! { "$inc": counters },
+            None,
+        )
+        .await?;
+    if result.matched_count == 0 {
+        return Ok(false);
+    }
+
+    run_outputs(client).insert_one(output, None).await?;
+    Ok(true)
+}
+
+/// Finds the most recent output of another run which was rendered from the inputs identified by
+/// `cache_key`, whose blob is still kept.
+pub async fn find_reusable_output(
+    client: &mongodb::Client,
+    run_id: ObjectId,
+    cache_key: &str,
+) -> Result<Option<RunOutput>> {
+    let now = DateTime::from(OffsetDateTime::now_utc());
+    let pipeline = vec![
+        doc! { "$match": {
+            "cache_key": cache_key,
+            "run_id": { "$ne": run_id },
+            "blob_name": { "$ne": null },
+            "error": null,
+        } },
+        doc! { "$sort": { "created": -1 } },
+        doc! { "$lookup": {
+            "from": RUNS_COLLECTION,
+            "localField": "run_id",
+            "foreignField": "_id",
+            "as": "run",
+        } },
+        doc! { "$unwind": "$run" },
+        // Only runs whose outputs are still kept, and are not about to be deleted by the sweeper
+        doc! { "$match": {
+            "run.expired_at": null,
+            "$or": [
+                { "run.pinned": true },
+                { "run.expires_at": null },
+                { "run.expires_at": { "$gt": now } },
+            ],
+            "$nor": [{ "run.status": CompositorRunStatus::Cancelled, "run.delete_outputs": true }],
+        } },
+        doc! { "$limit": 1 },
+        doc! { "$project": { "run": 0 } },
+    ];
+    let mut outputs = run_outputs(client)
+        .aggregate(pipeline, None)
+        .await?
+        .with_type::<RunOutput>();
+    Ok(outputs.try_next().await?)
+}
+
+/// Adds an error to a run's error list and makes it the run's last error, returning `false` if
+/// the run is no longer leased to `worker`.
+pub async fn record_run_error(
+    client: &mongodb::Client,
+    id: ObjectId,
+    worker: &str,
+    error: &RunError,
+) -> Result<bool> {
+    let result = runs(client)
+        .update_one(
+            doc! { "_id": id, "lease_owner": worker },
+            doc! {
+                "$set": { "last_error": &error.message },
+                "$push": {
+                    "errors": { "$each": [bson::to_bson(error)?], "$slice": MAX_RECORDED_ERRORS },
+                },
+            },
+            None,
+        )
+        .await?;
+    Ok(result.matched_count > 0)
+}
+
+/// Returns a page of the files a run has rendered, in the order they were rendered.
+pub async fn get_run_output_files(
+    client: &mongodb::Client,
+    run_id: ObjectId,
+    page: usize,
+) -> Result<Vec<crate::models::OutputFile>> {
+    let filter = doc! { "run_id": run_id, "blob_name": { "$ne": null } };
+    let sort = doc! { "created": 1, "_id": 1 };
+    query_entities::<RunOutput, crate::models::OutputFile>(
+        client,
+        RUN_OUTPUTS_COLLECTION,
+        filter,
+        Some(sort),
+        page,
+    )
+    .await
+}
+
+/// Returns every file a run has rendered.
+pub async fn find_rendered_outputs(
+    client: &mongodb::Client,
+    run_id: ObjectId,
+) -> Result<Vec<RunOutput>> {
+    let outputs = run_outputs(client)
+        .find(
+            doc! { "run_id": run_id, "blob_name": { "$ne": null } },
+            None,
+        )
+        .await?
+        .try_collect()
+        .await?;
+    Ok(outputs)
+}
+
+/// Looks up a single rendered file of a run by its name within the run.
+pub async fn get_run_output_file(
+    client: &mongodb::Client,
+    run_id: ObjectId,
+    name: &str,
+) -> Result<Option<crate::models::OutputFile>> {
+    let blob_name = format!("{}/{}", run_id, name);
+    let output = run_outputs(client)
+        .find_one(doc! { "run_id": run_id, "blob_name": blob_name }, None)
+        .await?;
+    Ok(output.map(|o| o.into()))
+}
+
+/// Claims a finished run whose webhook is due, holding it for `lease` so no other dispatcher
+/// delivers it at the same time.
+pub async fn claim_webhook(
+    client: &mongodb::Client,
+    lease: Duration,
+) -> Result<Option<CompositorRun>> {
+    let now = OffsetDateTime::now_utc();
+    let filter = doc! {
+        "status": { "$in": &CompositorRunStatus::TERMINAL[..] },
+        "webhook.status": WebhookStatus::Pending,
+        "webhook.next_attempt": { "$lte": DateTime::from(now) },
+    };
+    let update = doc! {
+        "$set": { "webhook.next_attempt": DateTime::from(now + lease) },
+        "$inc": { "webhook.attempts": 1 },
+    };
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+
+    Ok(runs(client)
+        .find_one_and_update(filter, update, options)
+        .await?)
+}
+
+/// Records a webhook delivery attempt, scheduling the next attempt if there is one.
+pub async fn record_webhook_delivery(
+    client: &mongodb::Client,
+    id: ObjectId,
+    delivery: &WebhookDelivery,
+    status: WebhookStatus,
+    next_attempt: OffsetDateTime,
+) -> Result<()> {
+    runs(client)
+        .update_one(
+            doc! { "_id": id },
+            doc! {
+                "$set": {
+                    "webhook.status": status,
+                    "webhook.next_attempt": DateTime::from(next_attempt),
+                },
+                "$push": { "webhook.deliveries": bson::to_bson(delivery)? },
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
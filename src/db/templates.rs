@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+
+use bson::doc;
+use futures::TryStreamExt;
+use mongodb::error::{ErrorKind, WriteError, WriteFailure};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::models::Parameter;
+use crate::util::Result;
+
+use super::{get_entities, DateTime};
+
+const TEMPLATES_COLLECTION: &str = "templates";
+/// How often to retry saving a version when another one is saved at the same time.
+const MAX_SAVE_ATTEMPTS: usize = 3;
+
+/// A named template along with every version of it that was ever saved.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SavedTemplate {
+    #[serde(rename = "_id")]
+    pub name: String,
+    pub description: String,
+    pub created: DateTime,
+    pub last_modified: DateTime,
+    /// The version runs use unless they ask for another.
+    pub latest_version: u32,
+    /// Oldest first. Versions are never changed or removed once saved.
+    pub versions: Vec<TemplateVersion>,
+}
+
+impl SavedTemplate {
+    pub fn version(&self, version: u32) -> Option<&TemplateVersion> {
+        self.versions.iter().find(|v| v.version == version)
+    }
+
+    pub fn latest(&self) -> Option<&TemplateVersion> {
+        self.version(self.latest_version)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TemplateVersion {
+    pub version: u32,
+    #[serde(flatten)]
+    pub source: TemplateSource,
+    pub created: DateTime,
+    /// The version this one restored, if it was created by a rollback.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rolled_back_from: Option<u32>,
+}
+
+/// A template as it was saved, with placeholders for its parameters still in it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TemplateSource {
+    pub template: serde_json::Value,
+    #[serde(default)]
+    pub parameters: BTreeMap<String, Parameter>,
+    /// The saved template this one overrides parts of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<BaseTemplate>,
+}
+
+/// A saved template which another is based on.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BaseTemplate {
+    pub name: String,
+    /// Follows the latest version if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+}
+
+/// Identifies the version of a saved template a run was started from.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TemplateRef {
+    pub name: String,
+    pub version: u32,
+    /// The values given for the template's parameters.
+    #[serde(default)]
+    pub parameters: serde_json::Map<String, serde_json::Value>,
+    /// The versions of the templates it extends which were merged into it, most derived first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bases: Vec<BaseTemplate>,
+}
+
+impl From<SavedTemplate> for crate::models::SavedTemplate {
+    fn from(value: SavedTemplate) -> Self {
+        let source = value.latest().map(|v| v.source.clone());
+        let (template, parameters, extends) = match source {
+            Some(source) => (Some(source.template), source.parameters, source.extends),
+            None => (None, BTreeMap::new(), None),
+        };
+        Self {
+            name: value.name,
+            description: value.description,
+            created: value.created,
+            last_modified: value.last_modified,
+            latest_version: value.latest_version,
+            template,
+            parameters,
+            extends,
+        }
+    }
+}
+
+fn templates(client: &mongodb::Client) -> mongodb::Collection<SavedTemplate> {
+    client
+        .default_database()
+        .unwrap()
+        .collection(TEMPLATES_COLLECTION)
+}
+
+/// Saves a new template as its first version, returning `false` if the name is taken.
+pub async fn create_template(
+    client: &mongodb::Client,
+    name: &str,
+    description: String,
+    source: TemplateSource,
+) -> Result<bool> {
+    let now = DateTime::from(OffsetDateTime::now_utc());
+    let saved = SavedTemplate {
+        name: name.to_string(),
+        description,
+        created: now,
+        last_modified: now,
+        latest_version: 1,
+        versions: vec![TemplateVersion {
+            version: 1,
+            source,
+            created: now,
+            rolled_back_from: None,
+        }],
+    };
+    // The name is the id, so a template saved meanwhile fails the insert
+    match templates(client).insert_one(&saved, None).await {
+        Ok(_) => Ok(true),
+        Err(e) if is_duplicate_key(&e) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(WriteError { code: 11000, .. }))
+    )
+}
+
+pub async fn get_templates(
+    client: &mongodb::Client,
+    page: usize,
+) -> Result<Vec<crate::models::SavedTemplate>> {
+    get_entities::<SavedTemplate, crate::models::SavedTemplate>(client, TEMPLATES_COLLECTION, page)
+        .await
+}
+
+pub async fn find_template(client: &mongodb::Client, name: &str) -> Result<Option<SavedTemplate>> {
+    Ok(templates(client)
+        .find_one(doc! { "_id": name }, None)
+        .await?)
+}
+
+/// Saves `source` as the newest version of a saved template, returning the new version or
+/// `None` if there is no such template.
+pub async fn add_template_version(
+    client: &mongodb::Client,
+    name: &str,
+    source: TemplateSource,
+    description: Option<String>,
+    rolled_back_from: Option<u32>,
+) -> Result<Option<u32>> {
+    for _ in 0..MAX_SAVE_ATTEMPTS {
+        let current = match find_template(client, name).await? {
+            Some(saved) => saved.latest_version,
+            None => return Ok(None),
+        };
+
+        let now = DateTime::from(OffsetDateTime::now_utc());
+        let version = TemplateVersion {
+            version: current + 1,
+            source: source.clone(),
+            created: now,
+            rolled_back_from,
+        };
+        let mut modifications = doc! {
+            "latest_version": version.version,
+            "last_modified": now,
+        };
+        if let Some(description) = &description {
+            modifications.insert("description", description);
+        }
+
+        // Only applies if nobody else saved a version since it was read
+        let result = templates(client)
+            .update_one(
+                doc! { "_id": name, "latest_version": current },
+                doc! {
+                    "$set": modifications,
+                    "$push": { "versions": bson::to_bson(&version)? },
+                },
+                None,
+            )
+            .await?;
+        if result.modified_count > 0 {
+            return Ok(Some(version.version));
+        }
+    }
+
+    Err(format!("template {} is being modified concurrently", name))?
+}
+
+/// Finds the saved templates with any version extending the template `name`.
+pub async fn find_extending_templates(
+    client: &mongodb::Client,
+    name: &str,
+) -> Result<Vec<SavedTemplate>> {
+    Ok(templates(client)
+        .find(doc! { "versions.extends.name": name }, None)
+        .await?
+        .try_collect()
+        .await?)
+}
+
+/// Deletes a saved template with its whole history, returning `false` if there was none.
+pub async fn delete_template(client: &mongodb::Client, name: &str) -> Result<bool> {
+    let result = templates(client)
+        .delete_one(doc! { "_id": name }, None)
+        .await?;
+    Ok(result.deleted_count > 0)
+}
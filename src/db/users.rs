@@ -34,7 +34,7 @@ impl From<crate::models::User> for User {
             partition_key: user.id.clone(),
             row_key: user.id,
             avatar_url: user.avatar_url,
-            created: user.created.into(),
+            created: user.created,
             username: user.username,
             display_name: user.display_name,
             _created_tag: (),
@@ -48,7 +48,7 @@ impl From<User> for crate::models::User {
             avatar_url: db_user.avatar_url,
             id: db_user.row_key,
             username: db_user.username,
-            created: db_user.created.into(),
+            created: db_user.created,
             display_name: db_user.display_name,
         }
     }
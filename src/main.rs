@@ -11,7 +11,14 @@ use azure_security_keyvault::KeyvaultClient;
 use azure_storage::StorageCredentials;
 use azure_storage_blobs::prelude::BlobServiceClient;
 
-use crate::{blueprint::compositor::Compositor, models::Template};
+use crate::blueprint::{
+    compositor::Compositor,
+    events::RunEvents,
+    image_cache::ImageCache,
+    retention,
+    webhooks::{WebhookDispatcher, WebhookSender},
+    worker::{self, Worker},
+};
 
 mod blueprint;
 mod db;
@@ -23,7 +30,13 @@ const STORAGE_ACCOUNT_NAME: &str = "blueprintstore";
 const STORAGE_ACCOUNT_KEY_NAME: &str = "blueprintstore-key";
 const DB_CONN_STRING_NAME: &str = "blueprintdb-connstring";
 const KEYVAULT_URI: &str = "https://blueprint-kv.vault.azure.net/";
+/// The environment variable holding the URL the API is reached at, which links sent to webhooks
+/// are built from.
+const PUBLIC_URL_VAR: &str = "BLUEPRINT_PUBLIC_URL";
+const DEFAULT_PUBLIC_URL: &str = "http://127.0.0.1:8080";
 const NUM_TEMPLATE_WORKERS: usize = 10;
+const ASSET_CACHE_BUDGET: usize = 1024 * 1024 * 1024;
+const LAYER_CACHE_BUDGET: usize = 1024 * 1024 * 1024;
 
 #[get("/")]
 async fn index() -> impl Responder {
@@ -56,23 +69,50 @@ async fn main() -> std::io::Result<()> {
     db_client_options.default_database = Some(String::from("db"));
     let db_client = mongodb::Client::with_options(db_client_options).unwrap();
 
-    let compositor = Compositor::new(db_client.clone(), blob_service.clone());
+    let image_cache = Arc::new(ImageCache::new(
+        Arc::new(blob_service.clone()),
+        ASSET_CACHE_BUDGET,
+        LAYER_CACHE_BUDGET,
+    ));
+    let events = RunEvents::new();
+    let compositor = Compositor::new(
+        db_client.clone(),
+        blob_service.clone(),
+        image_cache.clone(),
+        events.clone(),
+    );
 
     // Template processing
-    let (template_queue, recv) = async_channel::unbounded::<Template>();
-    for _ in 0..NUM_TEMPLATE_WORKERS {
-        let compositor = compositor.clone();
-        let recv = recv.clone();
-        tokio::spawn(async move {
-            loop {
-                let template = recv.recv().await.expect("channel closed unexpectedly");
-                match compositor.run_template(template).await.unwrap() {
-                    (run_id, Ok(_)) => log::info!("template run {} succeeded", &run_id),
-                    (run_id, Err(e)) => log::error!("template run {} failed: {}", &run_id, e),
-                };
-            }
-        });
+    let instance_id = uuid::Uuid::new_v4();
+    for i in 0..NUM_TEMPLATE_WORKERS {
+        let worker = Worker::new(
+            format!("{}-{}", instance_id, i),
+            db_client.clone(),
+            compositor.clone(),
+        );
+        tokio::spawn(worker.run());
     }
+    tokio::spawn(worker::requeue_expired_leases(db_client.clone()));
+
+    let public_url = std::env::var(PUBLIC_URL_VAR).unwrap_or_else(|_| {
+        log::warn!(
+            "{} is not set, webhook links will point at {}",
+            PUBLIC_URL_VAR,
+            DEFAULT_PUBLIC_URL
+        );
+        DEFAULT_PUBLIC_URL.to_string()
+    });
+    let webhooks = WebhookDispatcher::new(
+        db_client.clone(),
+        WebhookSender::new().unwrap(),
+        public_url.trim_end_matches('/').to_string(),
+    );
+    tokio::spawn(webhooks.run());
+
+    tokio::spawn(retention::sweep_expired_runs(
+        db_client.clone(),
+        blob_service.clone(),
+    ));
 
     HttpServer::new(move || {
         let cors = Cors::default()
@@ -82,9 +122,10 @@ async fn main() -> std::io::Result<()> {
             .wrap(Logger::default())
             .wrap(cors)
             .wrap(middleware::Compress::default())
-            .app_data(web::Data::new(template_queue.clone()))
             .app_data(web::Data::new(blob_service.clone()))
             .app_data(web::Data::new(db_client.clone()))
+            .app_data(web::Data::new(events.clone()))
+            .app_data(web::Data::from(image_cache.clone()))
             .app_data(
                 actix_multipart::form::MultipartFormConfig::default()
                     .total_limit(1024 * 1024 * 200),
@@ -11,3 +11,23 @@ pub struct AssetPack {
     pub last_modified: DateTime,
     pub version: String,
 }
+
+/// Where the files of a zip merged into a pack ended up, by path.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MergedAssets {
+    pub added: Vec<String>,
+    pub replaced: Vec<String>,
+    /// Files the pack already had, which were left as they were.
+    pub skipped: Vec<String>,
+}
+
+/// A file in an asset pack.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PackAsset {
+    pub path: String,
+    pub size: u64,
+    pub content_type: String,
+    /// The `(width, height)` in pixels, if the asset is an image.
+    pub dimensions: Option<(u32, u32)>,
+    pub last_modified: DateTime,
+}
@@ -1,10 +1,111 @@
+use std::collections::BTreeMap;
+
+use bson::oid::ObjectId;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::blueprint::diff::ImageDiff;
+use crate::db::{
+    CompositorRunStatus, DateTime, ResolvedAlias, RunError, RunProgress, TemplateRef, WebhookState,
+};
 
-use crate::db::{CompositorRunStatus, DateTime};
+use super::Template;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CompositorRun {
     pub id: String,
     pub created: DateTime,
     pub status: CompositorRunStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub progress: RunProgress,
+    pub started: Option<DateTime>,
+    pub finished: Option<DateTime>,
+    pub errors: Vec<RunError>,
+    pub callback_url: Option<String>,
+    pub webhook: Option<WebhookState>,
+    pub submitted_by: Option<String>,
+    pub expires_at: Option<DateTime>,
+    pub pinned: bool,
+    pub expired_at: Option<DateTime>,
+    pub template: Template,
+    pub resolved_assets: Vec<ResolvedAlias>,
+    pub rerun_of: Option<String>,
+    pub incremental: bool,
+    pub saved_template: Option<TemplateRef>,
+}
+
+/// Narrows down a listing of runs. Every criterion which is set must match.
+#[derive(Debug, Clone, Default)]
+pub struct RunFilter {
+    pub status: Option<CompositorRunStatus>,
+    pub created_after: Option<OffsetDateTime>,
+    pub created_before: Option<OffsetDateTime>,
+    pub submitted_by: Option<String>,
+}
+
+/// Settings for a run which are not part of the template itself.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RunOptions {
+    pub callback: Option<Callback>,
+    /// The id of the user who submitted the run.
+    pub submitted_by: Option<String>,
+    /// How many days to keep the run's outputs, instead of the default.
+    pub retention_days: Option<u32>,
+    /// The run this one is submitted again from.
+    pub rerun_of: Option<ObjectId>,
+    /// Bind exactly these assets instead of expanding the template's aliases.
+    pub pinned_assets: Option<Vec<ResolvedAlias>>,
+    /// Copy outputs from earlier runs which rendered the same inputs instead of rendering them.
+    pub incremental: bool,
+    /// The saved template the run is started from.
+    pub saved_template: Option<TemplateRef>,
+}
+
+/// An endpoint which is sent a POST request once a run finishes.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Callback {
+    pub url: String,
+    /// Used to sign deliveries with HMAC-SHA256 so the receiver can verify them.
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+/// A file rendered by a run.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutputFile {
+    pub name: String,
+    pub size: u64,
+    pub content_type: String,
+    pub bindings: BTreeMap<String, String>,
+    pub created: DateTime,
+}
+
+/// How the outputs of one run differ from those of another, matched by name.
+#[derive(Debug, Serialize, Clone)]
+pub struct RunDiff {
+    /// Outputs only the second run has.
+    pub added: Vec<String>,
+    /// Outputs only the first run has.
+    pub removed: Vec<String>,
+    pub changed: Vec<ChangedOutput>,
+    pub unchanged: usize,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ChangedOutput {
+    pub name: String,
+    #[serde(flatten)]
+    pub diff: ImageDiff,
+    /// Where to get an image highlighting the differing pixels.
+    pub diff_image: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunDetails {
+    #[serde(flatten)]
+    pub run: CompositorRun,
+    /// Where to list the files the run has rendered.
+    pub outputs: String,
 }
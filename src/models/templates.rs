@@ -1,16 +1,74 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::db::{BaseTemplate, DateTime};
+
+/// A saved template as of its latest version.
 #[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SavedTemplate {
+    pub name: String,
+    pub description: String,
+    pub created: DateTime,
+    pub last_modified: DateTime,
+    pub latest_version: u32,
+    pub template: Option<serde_json::Value>,
+    pub parameters: BTreeMap<String, Parameter>,
+    pub extends: Option<BaseTemplate>,
+}
+
+/// A value a saved template is run with, which its placeholders are filled in with.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Parameter {
+    #[serde(rename = "type")]
+    pub kind: ParameterType,
+    /// Used when a run does not give a value.
+    pub default: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ParameterType {
+    Number,
+    String,
+    /// A `#rrggbb` or `#rrggbbaa` color.
+    Color,
+    /// The slug of an asset pack.
+    Pack,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Template {
+    /// Names starting with `$` for lists of `pack:glob` references. A run renders every
+    /// combination of the assets its aliases match, named after the asset bound to `$fg`.
     pub aliases: HashMap<String, Vec<String>>,
+    /// Drawn bottom to top.
     pub layers: Vec<Layer>,
+    /// The `(width, height)` of every output in pixels.
     pub canvas_size: (u32, u32),
+    #[serde(default)]
+    pub on_error: ErrorPolicy,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// What a run does when a single combination fails to render.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+pub enum ErrorPolicy {
+    /// Stop the run and mark it as failed.
+    #[default]
+    FailFast,
+    /// Keep rendering the remaining combinations.
+    Continue,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Layer {
+    /// Lets a template extending this one override the layer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Either an alias, or a `pack:path` reference to a single asset.
     #[serde(rename = "use")]
     pub reference: String,
     #[serde(default)]
@@ -18,10 +76,11 @@ pub struct Layer {
     #[serde(default)]
     pub blend_mode: BlendMode,
     #[serde(default)]
+    #[schemars(range(min = 0, max = 1))]
     pub opacity: Opacity,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, JsonSchema)]
 #[serde(transparent)]
 pub struct Opacity(pub f32);
 
@@ -31,7 +90,7 @@ impl Default for Opacity {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, JsonSchema)]
 #[serde(transparent)]
 pub struct Scale(pub f32);
 
@@ -41,11 +100,11 @@ impl Default for Scale {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, JsonSchema)]
 #[serde(transparent)]
 pub struct Degrees(pub f32);
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, JsonSchema)]
 pub struct Transform {
     /// The `(x, y)` offset in pixels.
     #[serde(default)]
@@ -60,19 +119,14 @@ pub struct Transform {
     pub rotate: Degrees,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, JsonSchema)]
 pub enum BlendMode {
+    #[default]
     Normal,
     Multiply,
     Overlay,
 }
 
-impl Default for BlendMode {
-    fn default() -> Self {
-        BlendMode::Normal
-    }
-}
-
 impl Template {
     pub fn normalize_use_refs(&mut self) {
         // Insert underscore before existing aliases to avoid name clashes with auto aliases
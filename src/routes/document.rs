@@ -0,0 +1,104 @@
+use std::fmt;
+
+use actix_web::{dev::Payload, error, http::header, FromRequest, HttpMessage, HttpRequest};
+use bytes::BytesMut;
+use futures::{future::LocalBoxFuture, FutureExt, StreamExt};
+use serde::de::DeserializeOwned;
+
+/// The same as the default limit of `web::Json`.
+const MAX_DOCUMENT_SIZE: usize = 2 * 1024 * 1024;
+
+/// A request body written in JSON, YAML or TOML, as given by its content type. Bodies without a
+/// content type are read as JSON.
+pub struct Document<T>(pub T);
+
+impl<T> Document<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    fn of(req: &HttpRequest) -> Result<Format, actix_web::Error> {
+        let mime = match req.mime_type() {
+            Ok(Some(mime)) => mime,
+            Ok(None) => return Ok(Format::Json),
+            Err(e) => return Err(error::ErrorBadRequest(e)),
+        };
+        let subtype = mime.suffix().unwrap_or_else(|| mime.subtype());
+        match subtype.as_str() {
+            "json" => Ok(Format::Json),
+            "yaml" | "x-yaml" => Ok(Format::Yaml),
+            "toml" | "x-toml" => Ok(Format::Toml),
+            _ => Err(error::ErrorUnsupportedMediaType(format!(
+                "unsupported content type {}, expected JSON, YAML or TOML",
+                mime
+            ))),
+        }
+    }
+
+    /// Reads a body, with the line and column of the problem in any error.
+    fn parse<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::from_slice(body).map_err(|e| e.to_string()),
+            Format::Toml => {
+                let body = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+                toml::from_str(body).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "JSON"),
+            Format::Yaml => write!(f, "YAML"),
+            Format::Toml => write!(f, "TOML"),
+        }
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Document<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let format = Format::of(req);
+        let length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+        let mut payload = payload.take();
+
+        async move {
+            let format = format?;
+            if length.is_some_and(|length| length > MAX_DOCUMENT_SIZE) {
+                return Err(error::ErrorPayloadTooLarge("request body is too large"));
+            }
+
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > MAX_DOCUMENT_SIZE {
+                    return Err(error::ErrorPayloadTooLarge("request body is too large"));
+                }
+                body.extend_from_slice(&chunk);
+            }
+
+            format
+                .parse(&body)
+                .map(Document)
+                .map_err(|e| error::ErrorBadRequest(format!("invalid {} body: {}", format, e)))
+        }
+        .boxed_local()
+    }
+}
@@ -1,2 +1,3 @@
+pub mod document;
 pub mod util;
 pub mod v1;
@@ -7,6 +7,12 @@ pub fn created(req: HttpRequest, id: &str, body: impl Serialize) -> HttpResponse
         .json(body)
 }
 
+pub fn accepted(location: &str, body: impl Serialize) -> HttpResponse {
+    HttpResponse::Accepted()
+        .append_header((header::LOCATION, location))
+        .json(body)
+}
+
 pub fn download(file_name: &str, data: impl Into<bytes::Bytes>) -> HttpResponse {
     HttpResponse::Ok()
         .append_header(header::ContentDisposition::attachment(
@@ -1,15 +1,17 @@
 use actix_multipart::form::{tempfile::TempFile, text, MultipartForm};
 use actix_web::{
     delete, get,
-    http::StatusCode,
-    patch, post,
+    http::{header, StatusCode},
+    patch, post, put,
     web::{self, Json},
-    HttpResponse, Responder,
+    HttpRequest, HttpResponse, Responder,
 };
 use azure_storage_blobs::prelude::BlobServiceClient;
 use bson::doc;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
+use validator::Validate;
 
 use crate::{
     db::{self, DateTime},
@@ -20,10 +22,17 @@ use crate::{
 
 use super::Paginated;
 
+/// The largest file that can be uploaded to a pack on its own.
+const MAX_ASSET_SIZE: usize = 64 * 1024 * 1024;
+
 pub fn config(cfg: &mut actix_web::web::ServiceConfig) {
     cfg.service(download_asset)
         .service(get_packs)
         .service(get_pack)
+        .service(get_pack_assets)
+        .service(merge_pack_assets)
+        .service(put_pack_asset)
+        .service(delete_pack_asset)
         .service(create_pack)
         .service(update_pack)
         .service(delete_pack);
@@ -79,6 +88,160 @@ async fn get_pack(
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct AssetFilter {
+    /// Only list assets whose paths match this glob.
+    glob: Option<String>,
+}
+
+/// Lists the files in a pack, so globs can be written against them.
+#[get("packs/{pack_id}/assets")]
+async fn get_pack_assets(
+    db: web::Data<mongodb::Client>,
+    blobs: web::Data<BlobServiceClient>,
+    slug: web::Path<String>,
+    query: web::Query<Paginated>,
+    filter: web::Query<AssetFilter>,
+) -> Result<impl Responder> {
+    query.validate()?;
+    let slug = slug.into_inner();
+    let glob = filter.into_inner().glob.unwrap_or_else(|| "**".to_string());
+    if let Err(e) = globset::Glob::new(&glob) {
+        return Ok(HttpResponse::BadRequest().body(format!("invalid glob: {}", e)));
+    }
+    if db::find_existing_packs(&db, std::slice::from_ref(&slug))
+        .await?
+        .is_empty()
+    {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let page = query.into_inner().page.unwrap_or(1);
+    let assets = db::get_pack_assets(&blobs, &slug, &glob, page).await?;
+    Ok(HttpResponse::Ok().json(assets))
+}
+
+/// Whether a path names a file the same way as the paths of files unpacked from a zip.
+fn is_asset_path(path: &str) -> bool {
+    !path.contains('\\')
+        && path
+            .split('/')
+            .all(|segment| !matches!(segment, "" | "." | ".."))
+}
+
+#[derive(Debug, MultipartForm)]
+struct MergePack {
+    #[multipart]
+    file: TempFile,
+    /// Defaults to skipping files the pack already has.
+    existing: Option<text::Text<db::ExistingAssets>>,
+    version: Option<text::Text<String>>,
+}
+
+/// Unpacks a zip into an existing pack alongside the files it already has.
+#[post("packs/{pack_id}/assets")]
+async fn merge_pack_assets(
+    db: web::Data<mongodb::Client>,
+    blobs: web::Data<BlobServiceClient>,
+    slug: web::Path<String>,
+    MultipartForm(form): MultipartForm<MergePack>,
+) -> Result<impl Responder> {
+    let slug = slug.into_inner();
+    if db::find_existing_packs(&db, std::slice::from_ref(&slug))
+        .await?
+        .is_empty()
+    {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let existing = form.existing.map(|e| e.into_inner()).unwrap_or_default();
+    let version = form.version.map(|v| v.into_inner());
+    let merged = db::merge_zipped_pack(&blobs, form.file, &slug, existing).await?;
+    if !merged.added.is_empty() || !merged.replaced.is_empty() || version.is_some() {
+        db::touch_pack(&db, &slug, version).await?;
+    }
+
+    Ok(HttpResponse::Ok().json(merged))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChangeAsset {
+    /// Sets the version of the pack along with the change.
+    version: Option<String>,
+}
+
+/// Adds a single file to a pack, or replaces the one at the same path.
+#[put("packs/{pack_id}/assets/{path:.*}")]
+async fn put_pack_asset(
+    req: HttpRequest,
+    db: web::Data<mongodb::Client>,
+    blobs: web::Data<BlobServiceClient>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ChangeAsset>,
+    mut payload: web::Payload,
+) -> Result<impl Responder> {
+    let (slug, path) = path.into_inner();
+    if !is_asset_path(&path) {
+        return Ok(HttpResponse::BadRequest().body(format!("invalid asset path: {}", &path)));
+    }
+    if db::find_existing_packs(&db, std::slice::from_ref(&slug))
+        .await?
+        .is_empty()
+    {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let mut content = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if content.len() + chunk.len() > MAX_ASSET_SIZE {
+            return Ok(HttpResponse::PayloadTooLarge().finish());
+        }
+        content.extend_from_slice(&chunk);
+    }
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let replaced = db::put_pack_asset(&blobs, &slug, &path, content, content_type).await?;
+    db::touch_pack(&db, &slug, query.into_inner().version).await?;
+
+    if replaced {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    let location = req.url_for("put_pack_asset", [&slug, &path])?;
+    Ok(HttpResponse::Created()
+        .append_header((header::LOCATION, location.as_str()))
+        .finish())
+}
+
+#[delete("packs/{pack_id}/assets/{path:.*}")]
+async fn delete_pack_asset(
+    db: web::Data<mongodb::Client>,
+    blobs: web::Data<BlobServiceClient>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ChangeAsset>,
+) -> Result<impl Responder> {
+    let (slug, path) = path.into_inner();
+    if !is_asset_path(&path) {
+        return Ok(HttpResponse::BadRequest().body(format!("invalid asset path: {}", &path)));
+    }
+    if db::find_existing_packs(&db, std::slice::from_ref(&slug))
+        .await?
+        .is_empty()
+    {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    if !db::delete_pack_asset(&blobs, &slug, &path).await? {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    db::touch_pack(&db, &slug, query.into_inner().version).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
 #[post("packs/{pack_id}")]
 async fn create_pack(
     db: web::Data<mongodb::Client>,
@@ -94,7 +257,6 @@ async fn create_pack(
             .tags
             .into_inner()
             .split_terminator(',')
-            .into_iter()
             .map(|s| s.to_owned())
             .collect(),
         last_modified: OffsetDateTime::now_utc().into(),
@@ -106,12 +268,8 @@ async fn create_pack(
 
     // Upload pack data in the bg
     tokio::spawn(async move {
-        match db::upload_zipped_pack(&blobs, form.file, &slug).await {
-            Ok(_) => {}
-            Err(e) => {
-                log::error!("error uploading zip file for {}: {}", &slug, e);
-                return;
-            }
+        if let Err(e) = db::upload_zipped_pack(&blobs, form.file, &slug).await {
+            log::error!("error uploading zip file for {}: {}", &slug, e);
         }
     });
 
@@ -180,8 +338,7 @@ async fn download_asset(
     let tags = tags_response.tags;
     let file_name = tags
         .into_iter()
-        .filter(|(k, _)| k == "file_name")
-        .nth(0)
+        .find(|(k, _)| k == "file_name")
         .map(|(_, v)| v)
         .unwrap_or(asset_id);
 
@@ -0,0 +1,14 @@
+use actix_web::{get, web, HttpResponse, Responder};
+
+use crate::blueprint::image_cache::ImageCache;
+
+pub fn config(cfg: &mut actix_web::web::ServiceConfig) {
+    cfg.service(get_cache_stats);
+}
+
+/// How often the image cache shared by every run has been hit and missed since the process
+/// started.
+#[get("cache/stats")]
+async fn get_cache_stats(image_cache: web::Data<ImageCache>) -> impl Responder {
+    HttpResponse::Ok().json(image_cache.stats())
+}
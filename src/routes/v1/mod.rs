@@ -1,11 +1,9 @@
 mod assets;
+mod cache;
 mod runs;
+mod schema;
 mod template;
 mod users;
-pub use assets::*;
-pub use runs::*;
-pub use template::*;
-pub use users::*;
 
 use serde::{Deserialize, Serialize};
 use validator::Validate;
@@ -21,7 +19,9 @@ pub fn config(cfg: &mut actix_web::web::ServiceConfig) {
         actix_web::web::scope("v1")
             .configure(users::config)
             .configure(assets::config)
+            .configure(cache::config)
             .configure(template::config)
-            .configure(runs::config),
+            .configure(runs::config)
+            .configure(schema::config),
     );
 }
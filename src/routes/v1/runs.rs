@@ -1,68 +1,708 @@
-use std::io::{Cursor, Write};
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
 
-use crate::{models::CompositorRun, util::Result};
-use actix_web::{get, http::header, web, HttpResponse, Responder};
+use crate::{
+    blueprint::{
+        diff::{diff_images, highlight_diff, ImageDiff},
+        events::{RunEvent, RunEventKind, RunEvents},
+    },
+    db::{self, CompositorRunStatus},
+    models::{ChangedOutput, RunDetails, RunDiff, RunFilter, RunOptions},
+    routes::util::accepted,
+    util::{zip_stream::ZipStreamWriter, Result},
+};
+use actix_web::{
+    get, http::header, patch, post, web, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
 use azure_storage_blobs::prelude::BlobServiceClient;
-use bson::doc;
-use futures::TryStreamExt;
-use zip::write::FileOptions;
+use bson::oid::ObjectId;
+use bytes::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use validator::Validate;
+
+use super::{template::TemplateRun, Paginated};
+
+/// How often to send a comment on an idle event stream so proxies don't close it.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// How many output blobs to download ahead of the one being sent in a ZIP archive.
+const ZIP_PREFETCH: usize = 8;
+/// How many pairs of outputs to compare at once when diffing runs.
+const DIFF_CONCURRENCY: usize = 4;
 
 pub fn config(cfg: &mut actix_web::web::ServiceConfig) {
-    cfg.service(get_run).service(get_run_results_zip);
+    cfg.service(get_runs)
+        .service(get_run)
+        .service(update_run)
+        .service(get_run_results_zip)
+        .service(get_run_output_files)
+        .service(get_run_output_file)
+        .service(get_run_diff)
+        .service(get_run_diff_image)
+        .service(cancel_run)
+        .service(rerun)
+        .service(get_run_events);
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelRun {
+    /// Also delete whatever the run has already rendered.
+    #[serde(default)]
+    delete_outputs: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct ListRuns {
+    #[validate(range(min = 1, message = "must be an integer >= 1"))]
+    page: Option<usize>,
+    status: Option<CompositorRunStatus>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    created_after: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    created_before: Option<OffsetDateTime>,
+    submitted_by: Option<String>,
+}
+
+#[get("runs")]
+pub async fn get_runs(
+    db: web::Data<mongodb::Client>,
+    query: web::Query<ListRuns>,
+) -> Result<impl Responder> {
+    let query = query.into_inner();
+    query.validate()?;
+
+    let filter = RunFilter {
+        status: query.status,
+        created_after: query.created_after,
+        created_before: query.created_before,
+        submitted_by: query.submitted_by,
+    };
+    let runs = db::get_runs(&db, filter, query.page.unwrap_or(1)).await?;
+    Ok(HttpResponse::Ok().json(runs))
 }
 
 #[get("runs/{run_id}")]
 pub async fn get_run(
+    req: HttpRequest,
+    db: web::Data<mongodb::Client>,
+    run_id: web::Path<String>,
+) -> Result<impl Responder> {
+    let run = match db::get_run(&db, &run_id).await? {
+        Some(run) => run,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    // Runs may render any number of outputs, so they are listed a page at a time
+    let outputs = req
+        .url_for("get_run_output_files", [run_id.as_str()])?
+        .to_string();
+
+    Ok(HttpResponse::Ok().json(RunDetails { run, outputs }))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateRun {
+    #[serde(default)]
+    pinned: Option<bool>,
+    #[serde(default)]
+    retention_days: Option<u32>,
+}
+
+#[patch("runs/{run_id}")]
+pub async fn update_run(
+    db: web::Data<mongodb::Client>,
+    run_id: web::Path<String>,
+    update: web::Json<UpdateRun>,
+) -> Result<impl Responder> {
+    let run_id = match ObjectId::parse_str(run_id.as_str()) {
+        Ok(id) => id,
+        Err(_) => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let update = update.into_inner();
+
+    match db::update_run_retention(&db, run_id, update.pinned, update.retention_days).await? {
+        true => Ok(HttpResponse::NoContent().finish()),
+        false => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[post("runs/{run_id}/cancel")]
+pub async fn cancel_run(
+    db: web::Data<mongodb::Client>,
+    blobs: web::Data<BlobServiceClient>,
+    events: web::Data<RunEvents>,
+    run_id: web::Path<String>,
+    query: web::Query<CancelRun>,
+) -> Result<impl Responder> {
+    let run_id = match ObjectId::parse_str(run_id.as_str()) {
+        Ok(id) => id,
+        Err(_) => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let delete_outputs = query.into_inner().delete_outputs;
+
+    if !db::cancel_run(&db, run_id, delete_outputs).await? {
+        return match db::find_run(&db, run_id).await? {
+            Some(run) => Ok(HttpResponse::Conflict().body(format!(
+                "run has already finished with status {}",
+                run.status
+            ))),
+            None => Ok(HttpResponse::NotFound().finish()),
+        };
+    }
+
+    events.status(run_id, CompositorRunStatus::Cancelled);
+
+    if delete_outputs {
+        tokio::spawn(async move {
+            if let Err(e) = db::delete_run_blobs(&blobs, run_id).await {
+                log::error!("error deleting outputs of cancelled run {}: {}", &run_id, e);
+            }
+        });
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Which versions of its assets a run is rendered against when submitted again.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RerunAssets {
+    /// Expand the template's aliases again, picking up added and changed assets.
+    #[default]
+    Latest,
+    /// Bind exactly the assets the original run rendered.
+    Pinned,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rerun {
+    #[serde(default)]
+    assets: RerunAssets,
+    /// Copy outputs whose inputs have not changed instead of rendering them again.
+    #[serde(default)]
+    incremental: bool,
+}
+
+/// Submits the template of an earlier run again as a new run.
+#[post("runs/{run_id}/rerun")]
+pub async fn rerun(
+    req: HttpRequest,
+    db: web::Data<mongodb::Client>,
+    run_id: web::Path<String>,
+    query: web::Query<Rerun>,
+) -> Result<impl Responder> {
+    let run_id = match ObjectId::parse_str(run_id.as_str()) {
+        Ok(id) => id,
+        Err(_) => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let run = match db::find_run(&db, run_id).await? {
+        Some(run) => run,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let retention_days = run.retention_days();
+    let pinned_assets = match query.assets {
+        RerunAssets::Latest => None,
+        RerunAssets::Pinned if run.resolved_assets.is_empty() => {
+            return Ok(HttpResponse::Conflict().body("run never got as far as resolving its assets"))
+        }
+        RerunAssets::Pinned => Some(run.resolved_assets),
+    };
+    let options = RunOptions {
+        retention_days,
+        callback: run.callback,
+        submitted_by: run.submitted_by,
+        saved_template: run.saved_template,
+        rerun_of: Some(run_id),
+        pinned_assets,
+        incremental: query.incremental,
+    };
+
+    let run_id = db::enqueue_run(&db, run.template, options).await?.to_hex();
+    let location = req.url_for("get_run", [&run_id])?;
+
+    Ok(accepted(location.as_str(), TemplateRun { run_id }))
+}
+
+/// Streams status changes, completed outputs and errors of a run as server-sent events, starting
+/// with its current status and ending once it finishes.
+#[get("runs/{run_id}/events")]
+pub async fn get_run_events(
+    db: web::Data<mongodb::Client>,
+    events: web::Data<RunEvents>,
+    run_id: web::Path<String>,
+) -> Result<impl Responder> {
+    let run_id = match ObjectId::parse_str(run_id.as_str()) {
+        Ok(id) => id,
+        Err(_) => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    // Subscribe before reading the current status so no transition is missed in between
+    let receiver = events.subscribe();
+    let run = match db::find_run(&db, run_id).await? {
+        Some(run) => run,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let stream = EventStream {
+        db: db.into_inner(),
+        run_id,
+        receiver,
+        pending: Some(RunEventKind::Status { status: run.status }),
+        done: false,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]))
+        // Keep the compression middleware from buffering events
+        .insert_header(header::ContentEncoding::Identity)
+        .streaming(stream.into_stream()))
+}
+
+enum Next {
+    Event(RunEventKind),
+    /// Nothing happened for a while.
+    Idle,
+}
+
+struct EventStream {
+    db: std::sync::Arc<mongodb::Client>,
+    run_id: ObjectId,
+    receiver: Receiver<RunEvent>,
+    pending: Option<RunEventKind>,
+    done: bool,
+}
+
+impl EventStream {
+    fn into_stream(self) -> impl Stream<Item = std::result::Result<Bytes, serde_json::Error>> {
+        futures::stream::unfold(self, |mut state| async move {
+            if state.done {
+                return None;
+            }
+
+            let kind = match state.pending.take() {
+                Some(kind) => kind,
+                None => match state.next_event().await? {
+                    Next::Event(kind) => kind,
+                    Next::Idle => {
+                        return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), state))
+                    }
+                },
+            };
+
+            if let RunEventKind::Status { status } = &kind {
+                state.done = status.is_terminal();
+            }
+            let message = serde_json::to_string(&kind)
+                .map(|data| Bytes::from(format!("event: {}\ndata: {}\n\n", kind.name(), data)));
+            Some((message, state))
+        })
+    }
+
+    /// Waits for the next event of this run, returning `None` once no more events will arrive.
+    async fn next_event(&mut self) -> Option<Next> {
+        loop {
+            match tokio::time::timeout(KEEP_ALIVE_INTERVAL, self.receiver.recv()).await {
+                Err(_) => {
+                    // Events are only published by the instance rendering the run, so another
+                    // instance may have finished it without this one hearing about it
+                    let run = db::find_run(&self.db, self.run_id).await.ok()??;
+                    if run.status.is_terminal() {
+                        return Some(Next::Event(RunEventKind::Status { status: run.status }));
+                    }
+                    return Some(Next::Idle);
+                }
+                Ok(Ok(event)) if event.run_id == self.run_id => {
+                    return Some(Next::Event(event.kind))
+                }
+                Ok(Ok(_)) => continue,
+                Ok(Err(RecvError::Lagged(_))) => {
+                    // Events were dropped, so resynchronize in case the run finished meanwhile
+                    let run = db::find_run(&self.db, self.run_id).await.ok()??;
+                    return Some(Next::Event(RunEventKind::Status { status: run.status }));
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+            }
+        }
+    }
+}
+
+#[get("runs/{run_id}/outputs")]
+pub async fn get_run_output_files(
     db: web::Data<mongodb::Client>,
     run_id: web::Path<String>,
+    query: web::Query<Paginated>,
+) -> Result<impl Responder> {
+    let run_id = match ObjectId::parse_str(run_id.as_str()) {
+        Ok(id) => id,
+        Err(_) => return Ok(HttpResponse::NotFound().finish()),
+    };
+    query.validate()?;
+
+    if db::find_run(&db, run_id).await?.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let files = db::get_run_output_files(&db, run_id, query.page.unwrap_or(1)).await?;
+    Ok(HttpResponse::Ok().json(files))
+}
+
+/// Downloads a single output of a run, honoring `If-None-Match` and single byte ranges.
+#[get("runs/{run_id}/outputs/{name:.*}")]
+pub async fn get_run_output_file(
+    req: HttpRequest,
+    db: web::Data<mongodb::Client>,
+    blob: web::Data<BlobServiceClient>,
+    path: web::Path<(String, String)>,
 ) -> Result<impl Responder> {
-    let run_id = run_id.into_inner();
-    let response = db
-        .default_database()
-        .unwrap()
-        .collection::<CompositorRun>("runs")
-        .find_one(doc! { "_id": &run_id }, None)
+    let (run_id, name) = path.into_inner();
+    let run_id = match ObjectId::parse_str(&run_id) {
+        Ok(id) => id,
+        Err(_) => return Ok(HttpResponse::NotFound().finish()),
+    };
+    if let Some(response) = outputs_unavailable(&db, run_id).await? {
+        return Ok(response);
+    }
+    let file = match db::get_run_output_file(&db, run_id, &name).await? {
+        Some(file) => file,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let blob = blob
+        .container_client("template-output")
+        .blob_client(format!("{}/{}", run_id, file.name));
+    let properties = blob.get_properties().await?.blob.properties;
+    let etag = header::EntityTag::new_strong(properties.etag.to_string().trim_matches('"').into());
+
+    let not_modified = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .finish());
+    }
+
+    let length = properties.content_length;
+    let range = match req.get_header::<header::Range>() {
+        Some(header::Range::Bytes(specs)) if specs.len() == 1 => {
+            match specs[0].to_satisfiable_range(length) {
+                Some(range) => Some(range),
+                None => {
+                    return Ok(HttpResponse::RangeNotSatisfiable()
+                        .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                            range: None,
+                            instance_length: Some(length),
+                        }))
+                        .finish())
+                }
+            }
+        }
+        // Multiple or non-byte ranges are answered with the whole file
+        _ => None,
+    };
+
+    let mut response = match range {
+        Some(_) => HttpResponse::PartialContent(),
+        None => HttpResponse::Ok(),
+    };
+    let mut get = blob.get();
+    if let Some((start, end)) = range {
+        get = get.range(start..end + 1);
+        response.insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+            range: Some((start, end)),
+            instance_length: Some(length),
+        }));
+    }
+    let size = range.map_or(length, |(start, end)| end - start + 1);
+    let body = get
+        .into_stream()
+        .map_ok(|chunk| chunk.data)
+        .try_flatten()
+        .map_err(io::Error::other);
+
+    Ok(response
+        .content_type(file.content_type)
+        .insert_header(header::ETag(etag))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .no_chunking(size)
+        .streaming(body))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffRuns {
+    /// How much a channel may differ before a pixel counts as changed.
+    #[serde(default)]
+    tolerance: u8,
+}
+
+/// Compares the outputs of run `a` to those of run `b`. Outputs rendered from the same inputs
+/// are not downloaded, since they cannot differ.
+#[get("runs/{run_a}/diff/{run_b}")]
+pub async fn get_run_diff(
+    req: HttpRequest,
+    db: web::Data<mongodb::Client>,
+    blob: web::Data<BlobServiceClient>,
+    path: web::Path<(String, String)>,
+    query: web::Query<DiffRuns>,
+) -> Result<impl Responder> {
+    let (run_a, run_b) = path.into_inner();
+    let (a, b) = match (ObjectId::parse_str(&run_a), ObjectId::parse_str(&run_b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    for id in [a, b] {
+        if let Some(response) = outputs_unavailable(&db, id).await? {
+            return Ok(response);
+        }
+    }
+
+    let by_name = |outputs: Vec<db::RunOutput>| -> BTreeMap<String, db::RunOutput> {
+        outputs
+            .into_iter()
+            .map(|o| {
+                (
+                    db::output_file_name(o.blob_name.as_deref().unwrap_or_default()).to_string(),
+                    o,
+                )
+            })
+            .collect()
+    };
+    let before = by_name(db::find_rendered_outputs(&db, a).await?);
+    let after = by_name(db::find_rendered_outputs(&db, b).await?);
+
+    let added = after
+        .keys()
+        .filter(|name| !before.contains_key(*name))
+        .cloned()
+        .collect();
+    let removed = before
+        .keys()
+        .filter(|name| !after.contains_key(*name))
+        .cloned()
+        .collect();
+
+    let mut unchanged = 0;
+    let mut pairs = Vec::new();
+    for (name, old) in &before {
+        let new = match after.get(name) {
+            Some(new) => new,
+            None => continue,
+        };
+        match (&old.cache_key, &new.cache_key) {
+            (Some(old_key), Some(new_key)) if old_key == new_key => unchanged += 1,
+            _ => pairs.push((name.clone(), old.blob_name.clone(), new.blob_name.clone())),
+        }
+    }
+
+    let container = blob.container_client("template-output");
+    let tolerance = query.tolerance;
+    let diffs: Vec<(String, ImageDiff)> = futures::stream::iter(pairs)
+        .map(|(name, old, new)| {
+            let container = container.clone();
+            async move {
+                let old = container
+                    .blob_client(old.unwrap_or_default())
+                    .get_content()
+                    .await?;
+                let new = container
+                    .blob_client(new.unwrap_or_default())
+                    .get_content()
+                    .await?;
+                let diff = web::block(move || {
+                    let (old, new) = (decode_output(&old)?, decode_output(&new)?);
+                    Ok::<_, image::ImageError>(diff_images(&old, &new, tolerance))
+                })
+                .await??;
+                Ok::<_, Box<dyn std::error::Error>>((name, diff))
+            }
+        })
+        .buffered(DIFF_CONCURRENCY)
+        .try_collect()
         .await?;
 
-    match response {
-        Some(run) => Ok(HttpResponse::Ok().json(run)),
-        None => Ok(HttpResponse::NotFound().finish()),
+    let mut changed = Vec::new();
+    for (name, diff) in diffs {
+        if diff.is_identical() {
+            unchanged += 1;
+            continue;
+        }
+        let mut diff_image = req.url_for("get_run_diff_image", [&run_a, &run_b, &name])?;
+        if tolerance > 0 {
+            diff_image.set_query(Some(&format!("tolerance={}", tolerance)));
+        }
+        changed.push(ChangedOutput {
+            name,
+            diff,
+            diff_image: diff_image.to_string(),
+        });
     }
+
+    Ok(HttpResponse::Ok().json(RunDiff {
+        added,
+        removed,
+        changed,
+        unchanged,
+    }))
+}
+
+/// Renders the output `name` of run `b` with the pixels that differ from run `a` highlighted.
+#[get("runs/{run_a}/diff/{run_b}/{name:.*}")]
+pub async fn get_run_diff_image(
+    db: web::Data<mongodb::Client>,
+    blob: web::Data<BlobServiceClient>,
+    path: web::Path<(String, String, String)>,
+    query: web::Query<DiffRuns>,
+) -> Result<impl Responder> {
+    let (run_a, run_b, name) = path.into_inner();
+    let (a, b) = match (ObjectId::parse_str(&run_a), ObjectId::parse_str(&run_b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    for id in [a, b] {
+        if let Some(response) = outputs_unavailable(&db, id).await? {
+            return Ok(response);
+        }
+    }
+
+    let container = blob.container_client("template-output");
+    let mut images = Vec::new();
+    for id in [a, b] {
+        if db::get_run_output_file(&db, id, &name).await?.is_none() {
+            return Ok(HttpResponse::NotFound().finish());
+        }
+        let blob_name = format!("{}/{}", id, name);
+        images.push(container.blob_client(blob_name).get_content().await?);
+    }
+
+    let (new, old) = (images.pop().unwrap(), images.pop().unwrap());
+    let tolerance = query.tolerance;
+    let png = web::block(move || {
+        let (old, new) = (decode_output(&old)?, decode_output(&new)?);
+        let mut png = Vec::new();
+        highlight_diff(&old, &new, tolerance)
+            .write_with_encoder(image::codecs::png::PngEncoder::new(&mut png))?;
+        Ok::<_, image::ImageError>(png)
+    })
+    .await??;
+
+    Ok(HttpResponse::Ok()
+        .content_type(db::OUTPUT_CONTENT_TYPE)
+        .body(png))
+}
+
+/// Answers for a run which does not exist, or whose outputs were deleted once its retention ran
+/// out.
+async fn outputs_unavailable(
+    db: &mongodb::Client,
+    run_id: ObjectId,
+) -> Result<Option<HttpResponse>> {
+    Ok(match db::find_run(db, run_id).await? {
+        Some(run) if run.expired_at.is_some() => {
+            Some(HttpResponse::Gone().body(format!("the outputs of run {} have expired", run_id)))
+        }
+        Some(_) => None,
+        None => Some(HttpResponse::NotFound().finish()),
+    })
+}
+
+fn decode_output(content: &[u8]) -> image::ImageResult<image::RgbaImage> {
+    Ok(image::load_from_memory(content)?.into_rgba8())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadRun {
+    /// Only include outputs whose file name matches this glob.
+    glob: Option<String>,
 }
 
+/// Streams the outputs of a run as a ZIP archive, fetching a few blobs ahead of what has been
+/// sent so far.
 #[get("runs/{run_id}/zip")]
 pub async fn get_run_results_zip(
+    db: web::Data<mongodb::Client>,
     blob: web::Data<BlobServiceClient>,
     run_id: web::Path<String>,
+    query: web::Query<DownloadRun>,
 ) -> Result<impl Responder> {
-    let run_id = run_id.into_inner();
+    let run_id = match ObjectId::parse_str(run_id.as_str()) {
+        Ok(id) => id,
+        Err(_) => return Ok(HttpResponse::NotFound().finish()),
+    };
+    if let Some(response) = outputs_unavailable(&db, run_id).await? {
+        return Ok(response);
+    }
+    let matcher = match query
+        .into_inner()
+        .glob
+        .map(|glob| globset::Glob::new(&glob))
+    {
+        Some(Ok(glob)) => Some(glob.compile_matcher()),
+        Some(Err(e)) => return Ok(HttpResponse::BadRequest().body(format!("invalid glob: {}", e))),
+        None => None,
+    };
+
     let container = blob.container_client("template-output");
     let pages: Vec<_> = container
         .list_blobs()
-        .prefix(run_id.clone())
+        .prefix(format!("{}/", &run_id))
         .into_stream()
         .try_collect()
         .await?;
 
-    let blobs = pages.iter().flat_map(|page| page.blobs.blobs());
+    let files: Vec<_> = pages
+        .iter()
+        .flat_map(|page| page.blobs.blobs())
+        .filter_map(|blob| {
+            let file_name = blob.name.split_once('/')?.1.to_string();
+            match &matcher {
+                Some(matcher) if !matcher.is_match(&file_name) => None,
+                _ => Some((blob.name.clone(), file_name, blob.properties.last_modified)),
+            }
+        })
+        .collect();
 
-    let mut buf = Vec::new();
-    {
-        let mut writer = zip::ZipWriter::new(Cursor::new(&mut buf));
-        let options = FileOptions::default();
-        for blob in blobs {
-            let content = container.blob_client(&blob.name).get_content().await?;
-            let file_name = blob.name.split_once('/').unwrap().1;
-            writer.start_file(file_name, options)?;
-            writer.write(&content)?;
-        }
-        writer.finish()?;
-    }
+    let downloads = futures::stream::iter(files)
+        .map(move |(blob_name, file_name, modified)| {
+            let blob = container.blob_client(blob_name);
+            async move {
+                let content = blob.get_content().await.map_err(io::Error::other)?;
+                Ok::<_, io::Error>((file_name, modified, Bytes::from(content)))
+            }
+        })
+        .buffered(ZIP_PREFETCH)
+        .boxed_local();
+
+    let archive_name = format!("{}.zip", &run_id);
+    let body = futures::stream::unfold(
+        (downloads, Some(ZipStreamWriter::new())),
+        |(mut downloads, writer)| async move {
+            let mut writer = writer?;
+            let chunks = match downloads.next().await {
+                Some(Ok((file_name, modified, content))) => {
+                    match writer.add_file(&file_name, modified, &content) {
+                        Ok(header) => vec![Ok(header), Ok(content)],
+                        Err(e) => return Some((vec![Err(e)], (downloads, None))),
+                    }
+                }
+                Some(Err(e)) => return Some((vec![Err(e)], (downloads, None))),
+                None => return Some((vec![Ok(writer.finish())], (downloads, None))),
+            };
+            Some((chunks, (downloads, Some(writer))))
+        },
+    )
+    .flat_map(futures::stream::iter)
+    .inspect_err(move |e| log::error!("error streaming outputs of run {}: {}", &run_id, e));
 
     Ok(HttpResponse::Ok()
-        .append_header(header::ContentDisposition::attachment(format!(
-            "{}.zip",
-            &run_id
-        )))
-        .body(buf))
+        .content_type("application/zip")
+        // Keep the compression middleware from buffering the archive of already compressed images
+        .insert_header(header::ContentEncoding::Identity)
+        .append_header(header::ContentDisposition::attachment(archive_name))
+        .streaming(body))
 }
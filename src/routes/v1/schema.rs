@@ -0,0 +1,17 @@
+use actix_web::{get, HttpResponse, Responder};
+use schemars::schema_for;
+
+use super::template::TemplateRequest;
+
+pub fn config(cfg: &mut actix_web::web::ServiceConfig) {
+    cfg.service(get_template_schema);
+}
+
+/// The JSON Schema of the body of `POST /v1/compositor`, for editors and CI to check templates
+/// against.
+#[get("schema/template.json")]
+async fn get_template_schema() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/schema+json")
+        .json(schema_for!(TemplateRequest))
+}
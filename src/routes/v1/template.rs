@@ -1,48 +1,428 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::{
-    models::{Layer, Template},
+    blueprint::{
+        image_cache::ImageCache,
+        inheritance, lint, parameters,
+        validation::{self, Problem, ValidationReport},
+    },
+    db::{self, BaseTemplate, TemplateRef, TemplateSource},
+    models::{Callback, ErrorPolicy, Layer, Parameter, RunOptions, Template},
+    routes::{document::Document, util::accepted},
     util::Result,
 };
-use actix_web::{post, web, HttpResponse, Responder};
-use async_channel::Sender;
+use actix_web::{delete, get, http::header, post, put, web, HttpRequest, HttpResponse, Responder};
+use azure_storage_blobs::prelude::BlobServiceClient;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use validator::Validate;
+
+use super::Paginated;
 
 pub fn config(cfg: &mut actix_web::web::ServiceConfig) {
-    cfg.service(run_template);
+    cfg.service(run_template)
+        // Before `create_template` so "validate" isn't taken for a template name
+        .service(validate_template)
+        .service(get_templates)
+        .service(get_template)
+        .service(create_template)
+        .service(update_template)
+        .service(delete_template)
+        .service(get_template_versions)
+        .service(get_template_version)
+        .service(rollback_template)
+        .service(run_saved_template);
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TemplateRun {
-    run_id: String,
+    pub run_id: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Settings for a run which are not part of the template itself.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct RunSettings {
+    #[serde(default)]
+    callback: Option<Callback>,
+    /// The id of the user submitting the run.
+    #[serde(default)]
+    submitted_by: Option<String>,
+    /// How many days to keep the run's outputs, instead of the default.
+    #[serde(default)]
+    retention_days: Option<u32>,
+    /// Copy outputs of earlier runs which rendered the same inputs instead of rendering them.
+    #[serde(default)]
+    incremental: bool,
+}
+
+impl RunSettings {
+    /// Checks the settings, returning the response to reject the request with if they are bad.
+    fn check(&self) -> Option<HttpResponse> {
+        let callback = self.callback.as_ref()?;
+        match reqwest::Url::parse(&callback.url) {
+            Ok(_) => None,
+            Err(e) => Some(HttpResponse::BadRequest().body(format!("invalid callback url: {}", e))),
+        }
+    }
+
+    fn into_options(self) -> RunOptions {
+        RunOptions {
+            callback: self.callback,
+            submitted_by: self.submitted_by,
+            retention_days: self.retention_days,
+            incremental: self.incremental,
+            ..Default::default()
+        }
+    }
+}
+
+/// A template to render, along with settings for the run rendering it.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct TemplateRequest {
+    /// Names starting with `$` for lists of `pack:glob` references. A run renders every
+    /// combination of the assets its aliases match, named after the asset bound to `$fg`.
     aliases: HashMap<String, Vec<String>>,
+    /// The `(width, height)` of every output in pixels.
     canvas_size: (u32, u32),
+    /// Drawn bottom to top.
     layers: Vec<Layer>,
+    #[serde(default)]
+    on_error: ErrorPolicy,
+    #[serde(flatten)]
+    settings: RunSettings,
 }
 
-impl From<TemplateRequest> for Template {
-    fn from(value: TemplateRequest) -> Self {
-        Self {
-            aliases: value.aliases,
-            canvas_size: value.canvas_size,
-            layers: value.layers,
-        }
+impl TemplateRequest {
+    /// Splits the request into the template to render and the settings for its run.
+    pub fn into_parts(self) -> (Template, RunOptions) {
+        let template = Template {
+            aliases: self.aliases,
+            canvas_size: self.canvas_size,
+            layers: self.layers,
+            on_error: self.on_error,
+        };
+        (template, self.settings.into_options())
     }
 }
 
 #[post("compositor")]
 async fn run_template(
-    queue: web::Data<Sender<Template>>,
-    template: web::Json<TemplateRequest>,
+    req: HttpRequest,
+    db: web::Data<mongodb::Client>,
+    template: Document<TemplateRequest>,
+) -> Result<impl Responder> {
+    let template = template.into_inner();
+    if let Some(response) = template.settings.check() {
+        return Ok(response);
+    }
+    let (template, options) = template.into_parts();
+    if let Some(response) = reject_invalid(&db, &template).await? {
+        return Ok(response);
+    }
+
+    let run_id = db::enqueue_run(&db, template, options).await?.to_hex();
+    let location = req.url_for("get_run", [&run_id])?;
+
+    Ok(accepted(location.as_str(), TemplateRun { run_id }))
+}
+
+/// Checks a template without running or saving it, along with warnings about layers which would
+/// not show up in its outputs.
+#[post("templates/validate")]
+async fn validate_template(
+    db: web::Data<mongodb::Client>,
+    blobs: web::Data<BlobServiceClient>,
+    image_cache: web::Data<ImageCache>,
+    template: Document<TemplateRequest>,
+) -> Result<impl Responder> {
+    let (template, _) = template.into_inner().into_parts();
+    let mut report = validation::validate_template(&db, &template).await?;
+    if report.is_valid() {
+        report.warnings = lint::lint_template(&blobs, &image_cache, &template).await?;
+    }
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Validates a template, returning the response to reject the request with if it is invalid.
+async fn reject_invalid(db: &mongodb::Client, template: &Template) -> Result<Option<HttpResponse>> {
+    let report = validation::validate_template(db, template).await?;
+    Ok((!report.is_valid()).then(|| HttpResponse::UnprocessableEntity().json(report)))
+}
+
+/// Checks a template to be saved as `name` by merging it with the templates it extends and filling
+/// in the defaults of its parameters, returning the response to reject the request with if it is
+/// invalid.
+async fn reject_invalid_source(
+    db: &mongodb::Client,
+    name: &str,
+    source: &TemplateSource,
+) -> Result<Option<HttpResponse>> {
+    let source = match inheritance::resolve(db, name, source, None).await? {
+        Ok(resolved) => resolved.source,
+        Err(problem) => return Ok(Some(reject_problems(vec![problem]))),
+    };
+    let errors = parameters::check_parameters(&source.parameters);
+    if !errors.is_empty() {
+        return Ok(Some(reject_problems(errors)));
+    }
+    match parameters::instantiate(&source.template, &source.parameters, &Map::new()) {
+        Ok(template) => reject_invalid(db, &template).await,
+        Err(errors) => Ok(Some(reject_problems(errors))),
+    }
+}
+
+fn reject_problems(errors: Vec<Problem>) -> HttpResponse {
+    HttpResponse::UnprocessableEntity().json(ValidationReport {
+        errors,
+        ..Default::default()
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SaveTemplate {
+    /// Left unchanged when saving a new version if not given.
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    parameters: BTreeMap<String, Parameter>,
+    /// The saved template to take everything this one does not give from.
+    #[serde(default)]
+    extends: Option<BaseTemplate>,
+    /// The template itself, which may contain `{{parameter}}` placeholders.
+    #[serde(flatten)]
+    template: Map<String, Value>,
+}
+
+impl SaveTemplate {
+    fn into_parts(self) -> (TemplateSource, Option<String>) {
+        let source = TemplateSource {
+            template: Value::Object(self.template),
+            parameters: self.parameters,
+            extends: self.extends,
+        };
+        (source, self.description)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SavedVersion {
+    version: u32,
+}
+
+#[get("templates")]
+async fn get_templates(
+    db: web::Data<mongodb::Client>,
+    query: web::Query<Paginated>,
 ) -> Result<impl Responder> {
-    let run_id = uuid::Uuid::new_v4().to_string();
-    let template: Template = template.into_inner().into();
+    let query = query.into_inner();
+    query.validate()?;
+    let templates = db::get_templates(&db, query.page.unwrap_or(1)).await?;
+    Ok(HttpResponse::Ok().json(templates))
+}
+
+#[get("templates/{name}")]
+async fn get_template(
+    db: web::Data<mongodb::Client>,
+    name: web::Path<String>,
+) -> Result<impl Responder> {
+    match db::find_template(&db, &name).await? {
+        Some(saved) => Ok(HttpResponse::Ok().json(crate::models::SavedTemplate::from(saved))),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[post("templates/{name}")]
+async fn create_template(
+    req: HttpRequest,
+    db: web::Data<mongodb::Client>,
+    name: web::Path<String>,
+    body: Document<SaveTemplate>,
+) -> Result<impl Responder> {
+    let name = name.into_inner();
+    if slug::slugify(&name) != name {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "template names must be slugs, like {}",
+            slug::slugify(&name)
+        )));
+    }
+
+    let (source, description) = body.into_inner().into_parts();
+    if let Some(response) = reject_invalid_source(&db, &name, &source).await? {
+        return Ok(response);
+    }
+    let description = description.unwrap_or_default();
+    if !db::create_template(&db, &name, description, source).await? {
+        return Ok(HttpResponse::Conflict().body(format!("template {} already exists", &name)));
+    }
+
+    let location = req.url_for("get_template", [&name])?;
+    Ok(HttpResponse::Created()
+        .append_header((header::LOCATION, location.as_str()))
+        .json(SavedVersion { version: 1 }))
+}
+
+/// Saves a new version of a template. Earlier versions are kept and can still be run.
+#[put("templates/{name}")]
+async fn update_template(
+    db: web::Data<mongodb::Client>,
+    name: web::Path<String>,
+    body: Document<SaveTemplate>,
+) -> Result<impl Responder> {
+    let (source, description) = body.into_inner().into_parts();
+    if let Some(response) = reject_invalid_source(&db, &name, &source).await? {
+        return Ok(response);
+    }
+    let broken = inheritance::check_dependents(&db, &name, &source).await?;
+    if !broken.is_empty() {
+        return Ok(reject_problems(broken));
+    }
+    match db::add_template_version(&db, &name, source, description, None).await? {
+        Some(version) => Ok(HttpResponse::Ok().json(SavedVersion { version })),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[delete("templates/{name}")]
+async fn delete_template(
+    db: web::Data<mongodb::Client>,
+    name: web::Path<String>,
+) -> Result<impl Responder> {
+    let dependents = db::find_extending_templates(&db, &name).await?;
+    if !dependents.is_empty() {
+        let names: Vec<_> = dependents.into_iter().map(|t| t.name).collect();
+        return Ok(HttpResponse::Conflict().body(format!(
+            "template {} is extended by {}",
+            &name,
+            names.join(", ")
+        )));
+    }
+    match db::delete_template(&db, &name).await? {
+        true => Ok(HttpResponse::NoContent().finish()),
+        false => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[get("templates/{name}/versions")]
+async fn get_template_versions(
+    db: web::Data<mongodb::Client>,
+    name: web::Path<String>,
+) -> Result<impl Responder> {
+    match db::find_template(&db, &name).await? {
+        Some(saved) => Ok(HttpResponse::Ok().json(saved.versions)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[get("templates/{name}/versions/{version}")]
+async fn get_template_version(
+    db: web::Data<mongodb::Client>,
+    path: web::Path<(String, u32)>,
+) -> Result<impl Responder> {
+    let (name, version) = path.into_inner();
+    let saved = db::find_template(&db, &name).await?;
+    match saved.as_ref().and_then(|saved| saved.version(version)) {
+        Some(version) => Ok(HttpResponse::Ok().json(version)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Rollback {
+    version: u32,
+}
+
+/// Saves an earlier version of a template again as its newest version.
+#[post("templates/{name}/rollback")]
+async fn rollback_template(
+    db: web::Data<mongodb::Client>,
+    name: web::Path<String>,
+    body: web::Json<Rollback>,
+) -> Result<impl Responder> {
+    let target = body.into_inner().version;
+    let saved = match db::find_template(&db, &name).await? {
+        Some(saved) => saved,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let source = match saved.version(target) {
+        Some(version) => version.source.clone(),
+        None => {
+            return Ok(HttpResponse::BadRequest()
+                .body(format!("template {} has no version {}", &name, target)))
+        }
+    };
+    let broken = inheritance::check_dependents(&db, &name, &source).await?;
+    if !broken.is_empty() {
+        return Ok(reject_problems(broken));
+    }
+
+    match db::add_template_version(&db, &name, source, None, Some(target)).await? {
+        Some(version) => Ok(HttpResponse::Ok().json(SavedVersion { version })),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunSavedTemplate {
+    /// Defaults to the latest version.
+    #[serde(default)]
+    version: Option<u32>,
+    /// Values for the template's parameters, which otherwise take their defaults.
+    #[serde(default)]
+    parameters: Map<String, Value>,
+    #[serde(flatten)]
+    settings: RunSettings,
+}
+
+#[post("templates/{name}/runs")]
+async fn run_saved_template(
+    req: HttpRequest,
+    db: web::Data<mongodb::Client>,
+    name: web::Path<String>,
+    body: Document<RunSavedTemplate>,
+) -> Result<impl Responder> {
+    let body = body.into_inner();
+    if let Some(response) = body.settings.check() {
+        return Ok(response);
+    }
+
+    let saved = match db::find_template(&db, &name).await? {
+        Some(saved) => saved,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let version = body.version.unwrap_or(saved.latest_version);
+    let source = match saved.version(version) {
+        Some(version) => version.source.clone(),
+        None => {
+            return Ok(HttpResponse::BadRequest()
+                .body(format!("template {} has no version {}", &name, version)))
+        }
+    };
+    let resolved = match inheritance::resolve(&db, &saved.name, &source, None).await? {
+        Ok(resolved) => resolved,
+        Err(problem) => return Ok(reject_problems(vec![problem])),
+    };
+    let source = resolved.source;
+    let template =
+        match parameters::instantiate(&source.template, &source.parameters, &body.parameters) {
+            Ok(template) => template,
+            Err(errors) => return Ok(reject_problems(errors)),
+        };
+    if let Some(response) = reject_invalid(&db, &template).await? {
+        return Ok(response);
+    }
 
-    queue.send(template).await?;
+    let options = RunOptions {
+        saved_template: Some(TemplateRef {
+            name: saved.name,
+            version,
+            parameters: body.parameters,
+            bases: resolved.bases,
+        }),
+        ..body.settings.into_options()
+    };
+    let run_id = db::enqueue_run(&db, template, options).await?.to_hex();
+    let location = req.url_for("get_run", [&run_id])?;
 
-    Ok(HttpResponse::Accepted().json(TemplateRun { run_id }))
+    Ok(accepted(location.as_str(), TemplateRun { run_id }))
 }
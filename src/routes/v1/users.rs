@@ -36,8 +36,8 @@ pub async fn add_user(
 ) -> Result<impl Responder> {
     let body = body.into_inner();
     let username = body.username.clone();
-    let user = db::create_new_user(&client, body).await?;
-    Ok(created(req, &username, &user))
+    db::create_new_user(&client, body).await?;
+    Ok(created(req, &username, ()))
 }
 
 #[get("users/{id}")]
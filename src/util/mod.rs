@@ -1 +1,3 @@
+pub mod zip_stream;
+
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
@@ -0,0 +1,307 @@
+//! A minimal ZIP writer which produces the archive as a sequence of chunks, so it can be sent
+//! while it is being built instead of being assembled in memory first.
+//!
+//! Entries are always STORE'd, which is what we want for already-compressed images and means each
+//! entry's header can be written up front. ZIP64 records are only emitted once the archive grows
+//! past what the classic format can address.
+
+use std::io;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use time::OffsetDateTime;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+
+/// Version 4.5, the first to support ZIP64.
+const VERSION: u16 = 45;
+/// File names are UTF-8.
+const FLAGS: u16 = 1 << 11;
+const METHOD_STORE: u16 = 0;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+struct CentralEntry {
+    name: String,
+    time: u16,
+    date: u16,
+    crc: u32,
+    size: u32,
+    offset: u64,
+}
+
+#[derive(Default)]
+pub struct ZipStreamWriter {
+    offset: u64,
+    entries: Vec<CentralEntry>,
+}
+
+impl ZipStreamWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file to the archive, returning the header which must be sent right before `data`.
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        modified: OffsetDateTime,
+        data: &[u8],
+    ) -> io::Result<Bytes> {
+        let size = u32::try_from(data.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is too large to be archived", name),
+            )
+        })?;
+        let (time, date) = dos_date_time(modified);
+        let entry = CentralEntry {
+            name: name.to_string(),
+            time,
+            date,
+            crc: crc32fast::hash(data),
+            size,
+            offset: self.offset,
+        };
+
+        let mut header = BytesMut::with_capacity(30 + name.len());
+        header.put_u32_le(LOCAL_HEADER_SIGNATURE);
+        header.put_u16_le(VERSION);
+        header.put_u16_le(FLAGS);
+        header.put_u16_le(METHOD_STORE);
+        header.put_u16_le(entry.time);
+        header.put_u16_le(entry.date);
+        header.put_u32_le(entry.crc);
+        header.put_u32_le(entry.size);
+        header.put_u32_le(entry.size);
+        header.put_u16_le(name.len() as u16);
+        header.put_u16_le(0);
+        header.put_slice(name.as_bytes());
+
+        self.offset += (header.len() + data.len()) as u64;
+        self.entries.push(entry);
+        Ok(header.freeze())
+    }
+
+    /// Returns the central directory which ends the archive.
+    pub fn finish(self) -> Bytes {
+        let mut out = BytesMut::new();
+        let directory_offset = self.offset;
+
+        for entry in &self.entries {
+            let needs_zip64 = entry.offset >= u32::MAX as u64;
+            out.put_u32_le(CENTRAL_HEADER_SIGNATURE);
+            out.put_u16_le(VERSION);
+            out.put_u16_le(VERSION);
+            out.put_u16_le(FLAGS);
+            out.put_u16_le(METHOD_STORE);
+            out.put_u16_le(entry.time);
+            out.put_u16_le(entry.date);
+            out.put_u32_le(entry.crc);
+            out.put_u32_le(entry.size);
+            out.put_u32_le(entry.size);
+            out.put_u16_le(entry.name.len() as u16);
+            out.put_u16_le(if needs_zip64 { 12 } else { 0 });
+            out.put_u16_le(0); // comment length
+            out.put_u16_le(0); // disk number
+            out.put_u16_le(0); // internal attributes
+            out.put_u32_le(0); // external attributes
+            out.put_u32_le(if needs_zip64 {
+                u32::MAX
+            } else {
+                entry.offset as u32
+            });
+            out.put_slice(entry.name.as_bytes());
+            if needs_zip64 {
+                out.put_u16_le(ZIP64_EXTRA_ID);
+                out.put_u16_le(8);
+                out.put_u64_le(entry.offset);
+            }
+        }
+
+        let directory_size = out.len() as u64;
+        let count = self.entries.len() as u64;
+        let needs_zip64 = count >= u16::MAX as u64
+            || directory_offset >= u32::MAX as u64
+            || directory_size >= u32::MAX as u64;
+
+        if needs_zip64 {
+            let end_offset = directory_offset + directory_size;
+            out.put_u32_le(ZIP64_END_SIGNATURE);
+            out.put_u64_le(44); // size of the rest of this record
+            out.put_u16_le(VERSION);
+            out.put_u16_le(VERSION);
+            out.put_u32_le(0); // this disk
+            out.put_u32_le(0); // disk with the central directory
+            out.put_u64_le(count);
+            out.put_u64_le(count);
+            out.put_u64_le(directory_size);
+            out.put_u64_le(directory_offset);
+
+            out.put_u32_le(ZIP64_LOCATOR_SIGNATURE);
+            out.put_u32_le(0);
+            out.put_u64_le(end_offset);
+            out.put_u32_le(1); // total disks
+        }
+
+        out.put_u32_le(END_SIGNATURE);
+        out.put_u16_le(0);
+        out.put_u16_le(0);
+        out.put_u16_le(count.min(u16::MAX as u64) as u16);
+        out.put_u16_le(count.min(u16::MAX as u64) as u16);
+        out.put_u32_le(directory_size.min(u32::MAX as u64) as u32);
+        out.put_u32_le(directory_offset.min(u32::MAX as u64) as u32);
+        out.put_u16_le(0); // comment length
+
+        out.freeze()
+    }
+}
+
+/// Packs a timestamp into MS-DOS time and date fields, which cannot represent years before 1980.
+fn dos_date_time(value: OffsetDateTime) -> (u16, u16) {
+    if value.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = ((value.hour() as u16) << 11)
+        | ((value.minute() as u16) << 5)
+        | (value.second() as u16 / 2);
+    let date = (((value.year() - 1980).min(127) as u16) << 9)
+        | ((u8::from(value.month()) as u16) << 5)
+        | value.day() as u16;
+    (time, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    use time::macros::datetime;
+
+    use super::*;
+
+    /// Writes an archive in one piece, as it would be sent.
+    fn archive(writer: &mut ZipStreamWriter, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, data) in files {
+            let modified = datetime!(2023-09-14 12:34:56 UTC);
+            out.extend_from_slice(&writer.add_file(name, modified, data).unwrap());
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    fn read_all(archive: &mut zip::ZipArchive<impl Read + Seek>) -> Vec<(String, Vec<u8>)> {
+        (0..archive.len())
+            .map(|i| {
+                let mut file = archive.by_index(i).unwrap();
+                let mut data = Vec::new();
+                // Checks the CRC as well
+                file.read_to_end(&mut data).unwrap();
+                (file.name().to_string(), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn archive_reads_back() {
+        let mut writer = ZipStreamWriter::new();
+        let mut out = archive(
+            &mut writer,
+            &[("a.png", b"first"), ("dir/b.png", b""), ("c.png", b"third")],
+        );
+        out.extend_from_slice(&writer.finish());
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(out)).unwrap();
+        assert_eq!(
+            read_all(&mut archive),
+            vec![
+                ("a.png".to_string(), b"first".to_vec()),
+                ("dir/b.png".to_string(), b"".to_vec()),
+                ("c.png".to_string(), b"third".to_vec()),
+            ]
+        );
+        let modified = archive.by_index(0).unwrap().last_modified();
+        assert_eq!(
+            (modified.year(), modified.month(), modified.day()),
+            (2023, 9, 14)
+        );
+        assert_eq!(
+            (modified.hour(), modified.minute(), modified.second()),
+            (12, 34, 56)
+        );
+    }
+
+    /// An archive which starts `padding` zeroes into the file, without keeping them in memory.
+    struct Padded {
+        padding: u64,
+        archive: Vec<u8>,
+        position: u64,
+    }
+
+    impl Read for Padded {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = if self.position < self.padding {
+                let len = buf.len().min((self.padding - self.position) as usize);
+                buf[..len].fill(0);
+                len
+            } else {
+                let start = ((self.position - self.padding) as usize).min(self.archive.len());
+                let len = buf.len().min(self.archive.len() - start);
+                buf[..len].copy_from_slice(&self.archive[start..start + len]);
+                len
+            };
+            self.position += len as u64;
+            Ok(len)
+        }
+    }
+
+    impl Seek for Padded {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            let end = self.padding + self.archive.len() as u64;
+            self.position = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => end.checked_add_signed(offset).unwrap(),
+                SeekFrom::Current(offset) => self.position.checked_add_signed(offset).unwrap(),
+            };
+            Ok(self.position)
+        }
+    }
+
+    #[test]
+    fn archive_past_4_gib_uses_zip64() {
+        // As if more than 4 GiB of outputs had been sent already
+        let padding = u32::MAX as u64 + 10;
+        let mut writer = ZipStreamWriter {
+            offset: padding,
+            ..ZipStreamWriter::new()
+        };
+        let mut out = archive(&mut writer, &[("a.png", b"first"), ("b.png", b"second")]);
+        let directory = writer.finish();
+        // Followed by the ZIP64 locator and the classic end record
+        let zip64_end = directory.len() - 56 - 20 - 22;
+        assert_eq!(
+            u32::from_le_bytes(directory[zip64_end..][..4].try_into().unwrap()),
+            ZIP64_END_SIGNATURE
+        );
+        out.extend_from_slice(&directory);
+
+        let reader = Padded {
+            padding,
+            archive: out,
+            position: 0,
+        };
+        let mut archive = zip::ZipArchive::new(reader).unwrap();
+        assert_eq!(
+            read_all(&mut archive),
+            vec![
+                ("a.png".to_string(), b"first".to_vec()),
+                ("b.png".to_string(), b"second".to_vec()),
+            ]
+        );
+        // After the first entry's 30 byte header, its name and its data
+        let second = padding + 30 + 5 + 5;
+        assert_eq!(archive.by_index(1).unwrap().header_start(), second);
+    }
+}